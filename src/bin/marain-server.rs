@@ -1,17 +1,30 @@
 extern crate marain_server;
 
 use env_logger;
-use futures_channel::mpsc::unbounded;
+use futures_channel::mpsc::channel;
 use marain_server::{
     domain::commands::Command,
-    services::login::{create_key_pair, setup_listener, spawn_user_session},
-    workers::{app::App, app_gateway::AppGateway},
+    services::{
+        login::{
+            create_key_pair, getenv, key_fingerprint, reject_connection, setup_listener,
+            spawn_user_session,
+        },
+        transport::{AcceptFailure, ConnectionLimit},
+    },
+    workers::{
+        app::App,
+        app_gateway::{command_channel_capacity, AppGateway},
+    },
 };
 use tokio_tungstenite::tungstenite::Result;
 use x25519_dalek::{PublicKey, ReusableSecret};
 #[macro_use]
 extern crate lazy_static;
 
+const ACCEPT_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+/// Time given to sessions to tell their clients and drop their users before exiting.
+const SHUTDOWN_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+
 lazy_static! {
     pub static ref KEY_PAIR: (ReusableSecret, PublicKey) = create_key_pair();
     pub static ref SECRET_KEY: ReusableSecret = KEY_PAIR.0.clone();
@@ -21,30 +34,106 @@ lazy_static! {
 #[tokio::main]
 async fn main() -> Result<()> {
     let _ = env_logger::try_init();
-    let (app_sink, gateway_source) = unbounded::<Command>();
-    let (session_sink, session_worker_source) = unbounded::<Command>();
+    log::info!("Server key fingerprint: {}", key_fingerprint(&PUBLIC_KEY));
+    let capacity = command_channel_capacity();
+    let (app_sink, gateway_source) = channel::<Command>(capacity);
+    let (session_sink, session_worker_source) = channel::<Command>(capacity);
     let app_gateway = AppGateway::init(app_sink, session_worker_source);
 
     let app = App::init(gateway_source);
+    let app_shutdown = app.shutdown_sink();
     app.run();
     app_gateway.run();
+    #[cfg(feature = "metrics")]
+    spawn_metrics_listener();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let connection_limit =
+        ConnectionLimit::new(getenv("MARAIN_MAX_CONNECTIONS").parse::<usize>().ok());
     let listener = setup_listener().await;
     // Create the event loop and TCP listener we'll accept connections on.
-    while let Ok((stream, _)) = listener.accept().await {
-        match spawn_user_session(
-            stream,
-            session_sink.clone(),
-            (SECRET_KEY.clone(), *PUBLIC_KEY),
-        )
-        .await
-        {
-            Err(e) => {
-                log::error!("Could not spawn user_session due to error: {e}");
-                continue;
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => {
+                log::info!("Shutting down, no longer accepting connections");
+                let _ = app_shutdown.unbounded_send(());
+                tokio::time::sleep(SHUTDOWN_GRACE).await;
+                break;
             }
-            _ => {}
         };
+        let (stream, user_addr) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => match AcceptFailure::classify(&e) {
+                AcceptFailure::Connection => {
+                    log::warn!("Failed to accept a connection: {e}");
+                    continue;
+                }
+                AcceptFailure::Exhausted => {
+                    log::error!("Failed to accept connections, retrying shortly: {e}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+                AcceptFailure::Fatal => {
+                    log::error!("Listener failed, shutting down: {e}");
+                    break;
+                }
+            },
+        };
+        let Some(permit) = connection_limit.try_acquire() else {
+            log::warn!("Refusing {user_addr}, already at the connection limit");
+            reject_connection(stream, user_addr);
+            continue;
+        };
+        // Handshakes and login get their own task, so a stalled client cannot hold
+        // up other connections or the shutdown signal.
+        let session_sink = session_sink.clone();
+        tokio::spawn(async move {
+            if let Err(e) = spawn_user_session(
+                stream,
+                user_addr,
+                session_sink,
+                (SECRET_KEY.clone(), *PUBLIC_KEY),
+                permit,
+            )
+            .await
+            {
+                log::error!("Could not spawn user_session due to error: {e}");
+            }
+        });
     }
 
     Ok(())
 }
+
+/// Serves metrics on MARAIN_METRICS_PORT, if it is set.
+#[cfg(feature = "metrics")]
+fn spawn_metrics_listener() {
+    use marain_server::services::metrics;
+
+    let Ok(port) = getenv("MARAIN_METRICS_PORT").parse::<u16>() else {
+        return;
+    };
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(port).await {
+            log::error!("Metrics listener failed: {e}");
+        }
+    });
+}
+
+/// Resolves on SIGINT, or SIGTERM where there is one.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
@@ -1,36 +1,98 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use chrono::{DateTime, Utc};
 use marain_api::prelude::{ClientMsg, ClientMsgBody};
 
 use super::user::User;
 
+static NEXT_MESSAGE_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Hands out server assigned message ids, unique for the lifetime of the process.
+fn next_message_id() -> u64 {
+    NEXT_MESSAGE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Removes control characters, other than newline and tab, from text that came
+/// from a client. Escape sequences such as "\x1b[2J" go as a whole, so terminal
+/// clients are neither corrupted nor left showing the stray "[2J".
+pub fn sanitize_contents(text: &str) -> String {
+    let mut sanitized = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let csi = match c {
+            '\u{1b}' if chars.peek() == Some(&'[') => {
+                chars.next();
+                true
+            }
+            '\u{9b}' => true,
+            _ => false,
+        };
+        if csi {
+            // Parameters and intermediates run up to a final byte in @ to ~.
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        } else if !c.is_control() || c == '\n' || c == '\t' {
+            sanitized.push(c);
+        }
+    }
+    sanitized
+}
+
+/// The parent of a reply. The parent may since have been evicted from the room
+/// history, in which case the reply is still delivered but marked unavailable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplyTo {
+    pub id: u64,
+    pub available: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct MessageLog {
+    pub id: u64,
+    /// The id of the sending User, which unlike the name survives a rename.
+    pub author_id: String,
     pub username: String,
     pub timestamp: DateTime<Utc>,
     pub contents: String,
+    pub reply_to: Option<ReplyTo>,
 }
 
 impl MessageLog {
     pub fn from_user(user: &User, text: String) -> Self {
         Self {
+            id: next_message_id(),
+            author_id: user.id.clone(),
             username: user.name.clone(),
             timestamp: Utc::now(),
-            contents: text,
+            contents: sanitize_contents(&text),
+            reply_to: None,
         }
     }
 
-    pub fn from_client_msg(client_msg: ClientMsg, username: &str) -> Option<Self> {
+    pub fn with_reply_to(mut self, reply_to: ReplyTo) -> Self {
+        self.reply_to = Some(reply_to);
+        self
+    }
+
+    pub fn from_client_msg(client_msg: ClientMsg, user: &User) -> Option<Self> {
         match client_msg.body {
             ClientMsgBody::SendToRoom { contents } => Some(MessageLog {
-                username: username.into(),
+                id: next_message_id(),
+                author_id: user.id.clone(),
+                username: user.name.clone(),
                 timestamp: match client_msg.timestamp.into() {
                     Some(ts) => ts,
                     // just use now if we can't parse the raw ts
                     None => Utc::now(),
                 },
-                contents,
+                contents: sanitize_contents(&contents),
+                reply_to: None,
             }),
             _ => None,
         }
@@ -48,3 +110,24 @@ impl Display for MessageLog {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_sequences_and_control_characters_are_stripped() {
+        assert_eq!(sanitize_contents("\x1b[2Jhello\x1b[1;31m!"), "hello!");
+        assert_eq!(sanitize_contents("nul\0 bell\x07 c1\u{85}"), "nul bell c1");
+        assert_eq!(
+            sanitize_contents("two\nlines\tand a tab"),
+            "two\nlines\tand a tab"
+        );
+    }
+
+    #[test]
+    fn ordinary_unicode_is_untouched() {
+        let text = "héllo 👋🏽 你好 مرحبا";
+        assert_eq!(sanitize_contents(text), text);
+    }
+}
@@ -11,10 +11,214 @@ pub struct Command {
 
 #[derive(Debug, Clone)]
 pub enum CommandPayload {
-    RegisterUser(UnboundedSender<Event>),
-    DropUser,
-    MoveUser { target_room: Room },
-    RecordMessage { message: String },
+    /// The session's event channel and its generation, which tells apart two
+    /// connections for the same user when one resumes the other.
+    RegisterUser(UnboundedSender<Event>, u64),
+    /// Ignored unless the generation is that of the user's current registration,
+    /// so a session that was taken over cannot drop the one that resumed it.
+    DropUser(u64),
+    MoveUser {
+        target_room: Room,
+        /// When false the move is refused if the room does not exist yet.
+        create_if_missing: bool,
+    },
+    SwapRoom,
+    RecordMessage {
+        message: String,
+        reply_to: Option<u64>,
+    },
     GetRecipients,
+    GetRoomData,
     Time(Timestamp),
+    Help,
+    GetKeyFingerprint,
+    Ping,
+    /// Sent only to reset the session's idle timer, the session drops it.
+    KeepAlive,
+    GetLimits,
+    Page {
+        target_name: String,
+        message: String,
+    },
+    DirectMessage {
+        target_name: String,
+        message: String,
+    },
+    FetchNotifications {
+        limit: usize,
+    },
+    GlobalNotifications {
+        limit: usize,
+    },
+    RoomInfo {
+        room: Room,
+    },
+    ListRooms,
+    SearchRooms {
+        prefix: String,
+        limit: usize,
+    },
+    RoomRoster {
+        page: usize,
+    },
+    CrossPost {
+        message_id: u64,
+        target_room: Room,
+    },
+    UserRooms {
+        target_name: String,
+    },
+    SetMessageTtl {
+        seconds: Option<u64>,
+    },
+    SetCapacity {
+        capacity: Option<usize>,
+    },
+    SetHistoryLimit {
+        limit: usize,
+    },
+    GetMessage {
+        id: u64,
+    },
+    MoveAll {
+        from: Room,
+        to: Room,
+    },
+    Rename {
+        new_name: String,
+    },
+    EditMessage {
+        message_id: u64,
+        new_contents: String,
+    },
+    DeleteMessage {
+        message_id: u64,
+    },
+    CreateRoom {
+        room: Room,
+        capacity: Option<usize>,
+        read_only: bool,
+    },
+    CloseRoom {
+        room: Room,
+    },
+    KickUser {
+        target_name: String,
+        reason: String,
+    },
+    SetReadOnly {
+        read_only: bool,
+    },
+    Typing {
+        is_typing: bool,
+    },
+    /// Drops, or stops dropping, pages from the named user.
+    Ignore {
+        target_name: String,
+        ignore: bool,
+    },
+    /// Joins an existing room, or queues for it when it is full.
+    JoinOrWait {
+        room: Room,
+    },
+}
+
+/// Command kinds a client can ask for that the permissions file controls. The
+/// session lifecycle is always allowed, and so are time, help, keep_alive and
+/// get_key_fingerprint, which the session handles without asking the App.
+pub const CLIENT_COMMAND_KINDS: &[&str] = &[
+    "move_user",
+    "swap_room",
+    "record_message",
+    "get_recipients",
+    "get_room_data",
+    "ping",
+    "get_limits",
+    "page",
+    "direct_message",
+    "fetch_notifications",
+    "global_notifications",
+    "room_info",
+    "list_rooms",
+    "search_rooms",
+    "room_roster",
+    "cross_post",
+    "user_rooms",
+    "set_message_ttl",
+    "set_capacity",
+    "set_history_limit",
+    "get_message",
+    "move_all",
+    "rename",
+    "edit_message",
+    "delete_message",
+    "create_room",
+    "close_room",
+    "kick_user",
+    "set_read_only",
+    "typing",
+    "ignore",
+    "join_or_wait",
+];
+
+impl CommandPayload {
+    /// The name used for this kind of command in the permissions file.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CommandPayload::RegisterUser(..) => "register_user",
+            CommandPayload::DropUser(..) => "drop_user",
+            CommandPayload::MoveUser { .. } => "move_user",
+            CommandPayload::SwapRoom => "swap_room",
+            CommandPayload::RecordMessage { .. } => "record_message",
+            CommandPayload::GetRecipients => "get_recipients",
+            CommandPayload::GetRoomData => "get_room_data",
+            CommandPayload::Time(..) => "time",
+            CommandPayload::Help => "help",
+            CommandPayload::GetKeyFingerprint => "get_key_fingerprint",
+            CommandPayload::Ping => "ping",
+            CommandPayload::KeepAlive => "keep_alive",
+            CommandPayload::GetLimits => "get_limits",
+            CommandPayload::Page { .. } => "page",
+            CommandPayload::DirectMessage { .. } => "direct_message",
+            CommandPayload::FetchNotifications { .. } => "fetch_notifications",
+            CommandPayload::GlobalNotifications { .. } => "global_notifications",
+            CommandPayload::RoomInfo { .. } => "room_info",
+            CommandPayload::ListRooms => "list_rooms",
+            CommandPayload::SearchRooms { .. } => "search_rooms",
+            CommandPayload::RoomRoster { .. } => "room_roster",
+            CommandPayload::CrossPost { .. } => "cross_post",
+            CommandPayload::UserRooms { .. } => "user_rooms",
+            CommandPayload::SetMessageTtl { .. } => "set_message_ttl",
+            CommandPayload::SetCapacity { .. } => "set_capacity",
+            CommandPayload::SetHistoryLimit { .. } => "set_history_limit",
+            CommandPayload::GetMessage { .. } => "get_message",
+            CommandPayload::MoveAll { .. } => "move_all",
+            CommandPayload::Rename { .. } => "rename",
+            CommandPayload::EditMessage { .. } => "edit_message",
+            CommandPayload::DeleteMessage { .. } => "delete_message",
+            CommandPayload::CreateRoom { .. } => "create_room",
+            CommandPayload::CloseRoom { .. } => "close_room",
+            CommandPayload::KickUser { .. } => "kick_user",
+            CommandPayload::SetReadOnly { .. } => "set_read_only",
+            CommandPayload::Typing { .. } => "typing",
+            CommandPayload::Ignore { .. } => "ignore",
+            CommandPayload::JoinOrWait { .. } => "join_or_wait",
+        }
+    }
+
+    /// Commands only admins may use, refused for everyone else before they are handled.
+    pub fn requires_admin(&self) -> bool {
+        matches!(
+            self,
+            CommandPayload::GlobalNotifications { .. }
+                | CommandPayload::UserRooms { .. }
+                | CommandPayload::SetMessageTtl { .. }
+                | CommandPayload::SetCapacity { .. }
+                | CommandPayload::SetHistoryLimit { .. }
+                | CommandPayload::MoveAll { .. }
+                | CommandPayload::CloseRoom { .. }
+                | CommandPayload::KickUser { .. }
+                | CommandPayload::SetReadOnly { .. }
+        )
+    }
 }
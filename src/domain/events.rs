@@ -1,6 +1,47 @@
 // use super::{app::Room, chat_log::MessageLog, notification_log::NotificationLog, user::User};
 
-use super::{chat_log::MessageLog, notification_log::NotificationLog, room::Room, user::User};
+use chrono::{DateTime, Utc};
+
+use super::{
+    chat_log::MessageLog,
+    notification_log::NotificationLog,
+    room::Room,
+    user::{Role, User},
+};
+
+#[derive(Clone, Debug)]
+pub struct RosterEntry {
+    pub name: String,
+    pub role: Role,
+    pub joined_at: DateTime<Utc>,
+}
+
+/// Why a command failed, sent to the user who issued it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnknownCommand,
+    Forbidden,
+    MessageTooLong,
+    NotFound,
+    RateLimited,
+    InvalidArgument,
+    Internal,
+}
+
+impl ErrorCode {
+    /// Stable identifier clients can match on, in the style of disconnect reasons.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCode::UnknownCommand => "unknown_command",
+            ErrorCode::Forbidden => "forbidden",
+            ErrorCode::MessageTooLong => "message_too_long",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::RateLimited => "rate_limited",
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::Internal => "internal",
+        }
+    }
+}
 
 #[derive(Clone)]
 pub enum Event {
@@ -21,10 +62,118 @@ pub enum Event {
         notifications: Vec<NotificationLog>,
         occupant_names: Vec<String>,
     },
+    /// The same snapshot as UserJoined, for a client re-syncing without moving.
+    RoomData {
+        room: Room,
+        msg_log: Vec<MessageLog>,
+        notifications: Vec<NotificationLog>,
+        occupant_names: Vec<String>,
+    },
+    /// The room's occupants after someone joined or left, for live occupant lists.
+    OccupancyChanged {
+        room: Room,
+        occupant_names: Vec<String>,
+    },
+    JoinRejected {
+        room: Room,
+        reason: String,
+    },
+    /// The room was full, the user is queued for it at this position, counting
+    /// from 1.
+    Waitlisted {
+        room: Room,
+        position: usize,
+    },
     MsgReceived {
         msg: MessageLog,
     },
-    // Notify {
-    //     notice: Vec<NotificationLog>,
-    // }
+    UserRooms {
+        name: String,
+        rooms: Vec<String>,
+    },
+    /// Sent to a renamed user so their session carries the new User from then on.
+    Renamed {
+        old: User,
+        new: User,
+    },
+    DirectMsgReceived {
+        msg: MessageLog,
+    },
+    MessageEdited {
+        msg: MessageLog,
+    },
+    /// Sent only to the occupants mentioned in a message, alongside the usual
+    /// MsgReceived, so clients can alert for real mentions.
+    Mentioned {
+        msg: MessageLog,
+        mentioned_users: Vec<String>,
+    },
+    MsgDeleted {
+        id: u64,
+    },
+    SingleMessage {
+        message: Option<MessageLog>,
+    },
+    Notify {
+        notice: Vec<NotificationLog>,
+    },
+    NotificationPage {
+        notifications: Vec<NotificationLog>,
+    },
+    GlobalNotifications {
+        notifications: Vec<NotificationLog>,
+    },
+    RoomInfo {
+        room: Room,
+        exists: bool,
+        occupant_count: usize,
+    },
+    Recipients {
+        room: Option<Room>,
+        occupant_names: Vec<String>,
+    },
+    RoomList {
+        rooms: Vec<(String, usize)>,
+    },
+    RoomSearchResults {
+        rooms: Vec<(String, usize)>,
+    },
+    Roster {
+        room: Room,
+        members: Vec<RosterEntry>,
+        page: usize,
+        pages: usize,
+    },
+    Limits {
+        max_rooms: Option<usize>,
+        max_message_bytes: usize,
+        history_window: usize,
+        features: Vec<String>,
+    },
+    Pong {
+        server_version: String,
+        uptime_secs: u64,
+        active_connections: usize,
+    },
+    ServerStats {
+        connections: usize,
+        rooms: usize,
+        messages_per_min: usize,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// Ephemeral, never kept in the room history.
+    TypingChanged {
+        user: User,
+        room: Room,
+        is_typing: bool,
+    },
+    /// Sent to a kicked user, whose session tells the client why and ends.
+    Kicked {
+        reason: String,
+    },
+    /// The server is stopping, sessions should say so to their client and end.
+    ServerShutdown,
 }
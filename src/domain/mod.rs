@@ -2,5 +2,6 @@ pub mod chat_log;
 pub mod commands;
 pub mod events;
 pub mod notification_log;
+pub mod permissions;
 pub mod room;
 pub mod user;
@@ -1,8 +1,22 @@
 use chrono::{DateTime, Utc};
 
+use super::user::User;
+
+/// The notifier name attached to notices generated by the server itself.
+pub const SERVER_NOTIFIER: &str = "SERVER";
+
+/// How prominently a client should present a notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
 #[derive(Debug, Clone)]
 pub struct NotificationLog {
     pub notifier: String,
+    pub severity: Severity,
     pub timestamp: DateTime<Utc>,
     pub contents: String,
 }
@@ -10,9 +24,24 @@ pub struct NotificationLog {
 impl NotificationLog {
     pub fn new(text: String) -> Self {
         NotificationLog {
-            notifier: "SERVER".into(),
+            notifier: SERVER_NOTIFIER.into(),
+            severity: Severity::Info,
+            timestamp: Utc::now(),
+            contents: text,
+        }
+    }
+
+    pub fn from_user(user: &User, text: String) -> Self {
+        NotificationLog {
+            notifier: user.name.clone(),
+            severity: Severity::Info,
             timestamp: Utc::now(),
             contents: text,
         }
     }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
 }
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+
+use super::user::Role;
+
+/// Which roles may use each kind of command. Kinds that are not listed are
+/// open to every role, so an empty matrix allows everything.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    allowed: HashMap<String, Vec<Role>>,
+}
+
+impl Permissions {
+    /// Parses a matrix of the form
+    ///
+    /// ```toml
+    /// [commands]
+    /// page = ["admin"]
+    /// move_user = ["member", "admin"]
+    /// ```
+    ///
+    /// where an empty list disables the command entirely.
+    pub fn from_toml(contents: &str) -> Result<Self> {
+        let table: toml::Table = contents.parse()?;
+        let Some(commands) = table.get("commands") else {
+            return Ok(Self::default());
+        };
+        let commands = commands
+            .as_table()
+            .ok_or_else(|| anyhow!("commands must be a table"))?;
+
+        let mut allowed = HashMap::new();
+        for (kind, roles) in commands {
+            let roles = roles
+                .as_array()
+                .ok_or_else(|| anyhow!("roles for {kind} must be a list"))?
+                .iter()
+                .map(|role| match role.as_str() {
+                    Some("member") => Ok(Role::Member),
+                    Some("admin") => Ok(Role::Admin),
+                    _ => Err(anyhow!("unknown role {role} for {kind}")),
+                })
+                .collect::<Result<Vec<Role>>>()?;
+            allowed.insert(kind.clone(), roles);
+        }
+        Ok(Self { allowed })
+    }
+
+    pub fn allows(&self, kind: &str, role: Role) -> bool {
+        match self.allowed.get(kind) {
+            Some(roles) => roles.contains(&role),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlisted_commands_are_allowed() {
+        let permissions = Permissions::from_toml("[commands]\npage = [\"admin\"]").unwrap();
+
+        assert!(permissions.allows("move_user", Role::Member));
+        assert!(permissions.allows("page", Role::Admin));
+        assert!(!permissions.allows("page", Role::Member));
+    }
+
+    #[test]
+    fn empty_role_list_disables_a_command() {
+        let permissions = Permissions::from_toml("[commands]\ncross_post = []").unwrap();
+
+        assert!(!permissions.allows("cross_post", Role::Admin));
+    }
+
+    #[test]
+    fn unknown_roles_are_rejected() {
+        assert!(Permissions::from_toml("[commands]\npage = [\"owner\"]").is_err());
+    }
+}
@@ -1,8 +1,19 @@
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Member,
+    Admin,
+}
+
+use std::hash::{Hash, Hasher};
+
+/// A logged in user. Identity is the id alone, so a user stays the same key in
+/// every map after a rename or a change of secret.
+#[derive(Clone, Debug)]
 pub struct User {
     pub id: String,
     pub name: String,
     pub shared_secret: [u8; 32],
+    pub is_admin: bool,
 }
 
 impl User {
@@ -11,6 +22,59 @@ impl User {
             id,
             name,
             shared_secret,
+            is_admin: false,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        if self.is_admin {
+            Role::Admin
+        } else {
+            Role::Member
         }
     }
 }
+
+impl PartialEq for User {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for User {}
+
+impl Hash for User {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+
+    use super::*;
+
+    fn hash_of(user: &User) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        user.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn users_with_the_same_id_are_equal() {
+        let alice = User::new("1".into(), "alice".into(), [0; 32]);
+        let alicia = User::new("1".into(), "alicia".into(), [1; 32]);
+
+        assert_eq!(alice, alicia);
+        assert_eq!(hash_of(&alice), hash_of(&alicia));
+    }
+
+    #[test]
+    fn users_with_different_ids_are_not_equal() {
+        let alice = User::new("1".into(), "alice".into(), [0; 32]);
+        let other_alice = User::new("2".into(), "alice".into(), [0; 32]);
+
+        assert_ne!(alice, other_alice);
+    }
+}
@@ -0,0 +1,35 @@
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+/// Failures surfaced at the public boundaries of the command handler, the socket
+/// send adaptor and login, so callers can tell the kinds apart.
+#[derive(Debug, Error)]
+pub enum MarainServerError {
+    #[error("serialization failed: {0}")]
+    Serialization(String),
+    #[error("encryption failed: {0}")]
+    Encryption(String),
+    #[error("decryption failed: {0}")]
+    Decryption(String),
+    #[error("channel closed: {0}")]
+    ChannelClosed(String),
+    #[error("authentication failed: {0}")]
+    Auth(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    /// Boxed since tungstenite errors are large and would bloat every Result.
+    #[error(transparent)]
+    Transport(Box<tungstenite::Error>),
+}
+
+impl From<tungstenite::Error> for MarainServerError {
+    fn from(e: tungstenite::Error) -> Self {
+        MarainServerError::Transport(Box::new(e))
+    }
+}
+
+pub type Result<T> = std::result::Result<T, MarainServerError>;
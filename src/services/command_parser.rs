@@ -0,0 +1,428 @@
+/// A slash command recognised in text sent to a room.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedCommand {
+    Me {
+        action: String,
+    },
+    Help,
+    Fingerprint,
+    Limits,
+    Who,
+    Refresh,
+    List,
+    Ping,
+    KeepAlive,
+    Swap,
+    Roll {
+        sides: u32,
+    },
+    Page {
+        target_name: String,
+        message: String,
+    },
+    DirectMessage {
+        target_name: String,
+        message: String,
+    },
+    Notices {
+        limit: Option<usize>,
+    },
+    GlobalNotices {
+        limit: Option<usize>,
+    },
+    RoomInfo {
+        room: String,
+    },
+    SearchRooms {
+        prefix: String,
+        limit: Option<usize>,
+    },
+    Roster {
+        page: Option<usize>,
+    },
+    CrossPost {
+        message_id: u64,
+        target_room: String,
+    },
+    GetMessage {
+        message_id: u64,
+    },
+    MessageTtl {
+        seconds: Option<u64>,
+    },
+    Capacity {
+        capacity: Option<usize>,
+    },
+    History {
+        limit: usize,
+    },
+    UserRooms {
+        target_name: String,
+    },
+    MoveAll {
+        from: String,
+        to: String,
+    },
+    Reply {
+        message_id: u64,
+        message: String,
+    },
+    Nick {
+        new_name: String,
+    },
+    Edit {
+        message_id: u64,
+        new_contents: String,
+    },
+    Delete {
+        message_id: u64,
+    },
+    Join {
+        room: String,
+    },
+    Wait {
+        room: String,
+    },
+    Create {
+        room: String,
+        capacity: Option<usize>,
+        read_only: bool,
+    },
+    Close {
+        room: String,
+    },
+    Kick {
+        target_name: String,
+        reason: String,
+    },
+    ReadOnly {
+        read_only: bool,
+    },
+    Typing {
+        is_typing: bool,
+    },
+    Ignore {
+        target_name: String,
+        ignore: bool,
+    },
+}
+
+pub const DEFAULT_ROLL_SIDES: u32 = 6;
+
+/// Text listing the commands understood by parse, sent in reply to /help.
+pub const HELP_TEXT: &str = "Commands: /me <action>, /roll [sides], /page <name> <message>, /ignore <name>, /unignore <name>, /dm <name> <message>, \
+/notices [count], /globalnotices [count], /roominfo <room>, /list, /rooms <prefix> [count], /roster [page], \
+/crosspost <message id> <room>, /message <message id>, /ttl [seconds], /capacity [count], /history <count>, /userrooms <name>, /moveall <from> <to>, /join <room>, /wait <room>, /create <room> [capacity] [readonly], /close <room>, /kick <name> [reason], /readonly [on|off], /typing [on|off], /nick <name>, /swap, /ping, /keepalive, /fingerprint, /limits, /who, /refresh, /reply <message id> <message>, /edit <message id> <message>, /delete <message id>, /help";
+
+/// Parses room text as a slash command. Returns None when the text is not a
+/// command, names an unknown command, or is missing required arguments, in
+/// which case the text should be treated as an ordinary chat message.
+pub fn parse(contents: &str) -> Option<ParsedCommand> {
+    let command = contents.trim_start().strip_prefix('/')?;
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    let args = args.trim();
+
+    match name {
+        "me" if !args.is_empty() => Some(ParsedCommand::Me {
+            action: args.into(),
+        }),
+        "help" => Some(ParsedCommand::Help),
+        "swap" => Some(ParsedCommand::Swap),
+        "ping" => Some(ParsedCommand::Ping),
+        "keepalive" => Some(ParsedCommand::KeepAlive),
+        "fingerprint" => Some(ParsedCommand::Fingerprint),
+        "limits" => Some(ParsedCommand::Limits),
+        "who" => Some(ParsedCommand::Who),
+        "refresh" => Some(ParsedCommand::Refresh),
+        "list" => Some(ParsedCommand::List),
+        "roll" => Some(ParsedCommand::Roll {
+            sides: match args {
+                "" => DEFAULT_ROLL_SIDES,
+                sides => sides.parse().ok().filter(|sides| *sides > 0)?,
+            },
+        }),
+        "page" => {
+            let (target_name, message) = args.split_once(char::is_whitespace)?;
+            Some(ParsedCommand::Page {
+                target_name: target_name.into(),
+                message: message.trim().into(),
+            })
+        }
+        "dm" => {
+            let (target_name, message) = args.split_once(char::is_whitespace)?;
+            Some(ParsedCommand::DirectMessage {
+                target_name: target_name.into(),
+                message: message.trim().into(),
+            })
+        }
+        "notices" => Some(ParsedCommand::Notices {
+            limit: args.parse().ok(),
+        }),
+        "globalnotices" => Some(ParsedCommand::GlobalNotices {
+            limit: args.parse().ok(),
+        }),
+        "roominfo" if !args.is_empty() => Some(ParsedCommand::RoomInfo { room: args.into() }),
+        "rooms" if !args.is_empty() => {
+            let (prefix, limit) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            Some(ParsedCommand::SearchRooms {
+                prefix: prefix.into(),
+                limit: limit.trim().parse().ok(),
+            })
+        }
+        "roster" => Some(ParsedCommand::Roster {
+            page: args.parse().ok(),
+        }),
+        "crosspost" => {
+            let (message_id, target_room) = args.split_once(char::is_whitespace)?;
+            Some(ParsedCommand::CrossPost {
+                message_id: message_id.parse().ok()?,
+                target_room: target_room.trim().into(),
+            })
+        }
+        "message" => Some(ParsedCommand::GetMessage {
+            message_id: args.parse().ok()?,
+        }),
+        "ttl" => Some(ParsedCommand::MessageTtl {
+            seconds: match args {
+                "" | "0" => None,
+                seconds => Some(seconds.parse().ok()?),
+            },
+        }),
+        "capacity" => Some(ParsedCommand::Capacity {
+            capacity: match args {
+                "" => None,
+                capacity => Some(capacity.parse().ok()?),
+            },
+        }),
+        "history" => Some(ParsedCommand::History {
+            limit: args.parse().ok()?,
+        }),
+        "userrooms" if !args.is_empty() => Some(ParsedCommand::UserRooms {
+            target_name: args.into(),
+        }),
+        "moveall" => {
+            let (from, to) = args.split_once(char::is_whitespace)?;
+            Some(ParsedCommand::MoveAll {
+                from: from.into(),
+                to: to.trim().into(),
+            })
+        }
+        "nick" if !args.is_empty() && !args.contains(char::is_whitespace) => {
+            Some(ParsedCommand::Nick {
+                new_name: args.into(),
+            })
+        }
+        "edit" => {
+            let (message_id, new_contents) = args.split_once(char::is_whitespace)?;
+            Some(ParsedCommand::Edit {
+                message_id: message_id.parse().ok()?,
+                new_contents: new_contents.trim().into(),
+            })
+        }
+        "readonly" => Some(ParsedCommand::ReadOnly {
+            read_only: match args {
+                "" | "on" => true,
+                "off" => false,
+                _ => return None,
+            },
+        }),
+        "typing" => Some(ParsedCommand::Typing {
+            is_typing: match args {
+                "" | "on" => true,
+                "off" => false,
+                _ => return None,
+            },
+        }),
+        "kick" if !args.is_empty() => {
+            let (target_name, reason) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            Some(ParsedCommand::Kick {
+                target_name: target_name.into(),
+                reason: reason.trim().into(),
+            })
+        }
+        "ignore" | "unignore" if !args.is_empty() && !args.contains(char::is_whitespace) => {
+            Some(ParsedCommand::Ignore {
+                target_name: args.into(),
+                ignore: name == "ignore",
+            })
+        }
+        "join" if !args.is_empty() => Some(ParsedCommand::Join { room: args.into() }),
+        "wait" if !args.is_empty() => Some(ParsedCommand::Wait { room: args.into() }),
+        "create" => {
+            let mut words = args.split_whitespace();
+            let room = words.next()?.into();
+            let (mut capacity, mut read_only) = (None, false);
+            for word in words {
+                match word {
+                    "readonly" => read_only = true,
+                    capacity_arg => capacity = Some(capacity_arg.parse().ok()?),
+                }
+            }
+            Some(ParsedCommand::Create {
+                room,
+                capacity,
+                read_only,
+            })
+        }
+        "close" if !args.is_empty() => Some(ParsedCommand::Close { room: args.into() }),
+        "delete" => Some(ParsedCommand::Delete {
+            message_id: args.parse().ok()?,
+        }),
+        "reply" => {
+            let (message_id, message) = args.split_once(char::is_whitespace)?;
+            Some(ParsedCommand::Reply {
+                message_id: message_id.parse().ok()?,
+                message: message.trim().into(),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The names mentioned in a chat message as @name, in order and without
+/// duplicates. Trailing punctuation is not part of the name, so "@bob," is bob.
+pub fn mentions(contents: &str) -> Vec<String> {
+    let mut names: Vec<String> = vec![];
+    for word in contents.split_whitespace() {
+        let Some(name) = word.strip_prefix('@') else {
+            continue;
+        };
+        let name = name.trim_end_matches(|c: char| c.is_ascii_punctuation());
+        if !name.is_empty() && !names.iter().any(|seen| seen == name) {
+            names.push(name.into());
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_is_not_a_command() {
+        assert_eq!(parse("hello there"), None);
+        assert_eq!(parse("a /me in the middle"), None);
+        assert_eq!(parse(""), None);
+    }
+
+    #[test]
+    fn unknown_commands_are_not_parsed() {
+        assert_eq!(parse("/shrug"), None);
+        assert_eq!(parse("/"), None);
+    }
+
+    #[test]
+    fn leading_whitespace_is_ignored() {
+        assert_eq!(
+            parse("   /me waves"),
+            Some(ParsedCommand::Me {
+                action: "waves".into()
+            })
+        );
+    }
+
+    #[test]
+    fn required_arguments_must_be_present() {
+        assert_eq!(parse("/me"), None);
+        assert_eq!(parse("/me   "), None);
+        assert_eq!(parse("/page bob"), None);
+        assert_eq!(parse("/dm bob"), None);
+        assert_eq!(parse("/roominfo"), None);
+        assert_eq!(parse("/crosspost 12"), None);
+        assert_eq!(parse("/crosspost twelve lobby"), None);
+        assert_eq!(parse("/moveall lobby"), None);
+        assert_eq!(parse("/reply 12"), None);
+        assert_eq!(parse("/edit 12"), None);
+        assert_eq!(parse("/delete"), None);
+        assert_eq!(parse("/wait"), None);
+        assert_eq!(parse("/join"), None);
+        assert_eq!(parse("/close"), None);
+        assert_eq!(parse("/kick"), None);
+        assert_eq!(parse("/readonly maybe"), None);
+        assert_eq!(parse("/message"), None);
+        assert_eq!(parse("/nick"), None);
+        assert_eq!(parse("/nick two words"), None);
+        assert_eq!(parse("/ignore"), None);
+        assert_eq!(parse("/unignore bob smith"), None);
+    }
+
+    #[test]
+    fn optional_arguments_default_when_empty() {
+        assert_eq!(
+            parse("/notices"),
+            Some(ParsedCommand::Notices { limit: None })
+        );
+        assert_eq!(parse("/roster"), Some(ParsedCommand::Roster { page: None }));
+        assert_eq!(
+            parse("/roll"),
+            Some(ParsedCommand::Roll {
+                sides: DEFAULT_ROLL_SIDES
+            })
+        );
+    }
+
+    #[test]
+    fn arguments_are_split_on_any_whitespace() {
+        assert_eq!(
+            parse("/page\tbob  are you there?"),
+            Some(ParsedCommand::Page {
+                target_name: "bob".into(),
+                message: "are you there?".into()
+            })
+        );
+        assert_eq!(
+            parse("/crosspost 12 lobby"),
+            Some(ParsedCommand::CrossPost {
+                message_id: 12,
+                target_room: "lobby".into()
+            })
+        );
+    }
+
+    #[test]
+    fn create_takes_an_optional_capacity_and_read_only_flag() {
+        assert_eq!(
+            parse("/create stage readonly 20"),
+            Some(ParsedCommand::Create {
+                room: "stage".into(),
+                capacity: Some(20),
+                read_only: true
+            })
+        );
+        assert_eq!(
+            parse("/create stage"),
+            Some(ParsedCommand::Create {
+                room: "stage".into(),
+                capacity: None,
+                read_only: false
+            })
+        );
+        assert_eq!(parse("/create"), None);
+        assert_eq!(parse("/create stage big"), None);
+    }
+
+    #[test]
+    fn mentions_are_found_anywhere_in_the_text() {
+        assert_eq!(
+            mentions("@alice hi, and @bob, you too @alice"),
+            vec!["alice", "bob"]
+        );
+        assert!(mentions("email me at bob@example.com or @ me").is_empty());
+    }
+
+    #[test]
+    fn keepalive_takes_no_arguments() {
+        assert_eq!(parse("/keepalive"), Some(ParsedCommand::KeepAlive));
+        assert_eq!(parse("  /keepalive  "), Some(ParsedCommand::KeepAlive));
+    }
+
+    #[test]
+    fn roll_rejects_zero_or_invalid_sides() {
+        assert_eq!(parse("/roll 20"), Some(ParsedCommand::Roll { sides: 20 }));
+        assert_eq!(parse("/roll 0"), None);
+        assert_eq!(parse("/roll lots"), None);
+    }
+}
@@ -0,0 +1,62 @@
+use bincode::Options;
+use marain_api::prelude::ClientMsg;
+
+use super::login::getenv;
+
+/// The most a client message may decode to, unless MARAIN_MAX_DECODE_BYTES says
+/// otherwise. Comfortably above the chat message size limit, far below anything
+/// that could hurt the process.
+pub const DEFAULT_MAX_DECODE_BYTES: u64 = 64 * 1024;
+
+pub fn max_decode_bytes() -> u64 {
+    match getenv("MARAIN_MAX_DECODE_BYTES").parse::<u64>() {
+        Ok(limit) if limit > 0 => limit,
+        _ => DEFAULT_MAX_DECODE_BYTES,
+    }
+}
+
+/// Decodes a client message like bincode::deserialize does, but fails as soon as
+/// a length prefix would take it past max_decode_bytes instead of trying to
+/// allocate whatever an attacker claims.
+pub fn decode_client_msg(bytes: &[u8]) -> bincode::Result<ClientMsg> {
+    // bincode drops the limit when decoding from a slice, going through Read keeps it.
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(max_decode_bytes())
+        .deserialize_from(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use marain_api::prelude::{ClientMsgBody, Timestamp};
+
+    #[test]
+    fn decodes_what_bincode_serialize_encodes() {
+        let msg = ClientMsg {
+            token: None,
+            timestamp: Timestamp::from(Utc::now()),
+            body: ClientMsgBody::Move {
+                target: "lobby".into(),
+            },
+        };
+
+        let decoded = decode_client_msg(&bincode::serialize(&msg).unwrap()).unwrap();
+
+        assert!(matches!(decoded.body, ClientMsgBody::Move { target } if target == "lobby"));
+    }
+
+    #[test]
+    fn length_prefix_claiming_a_gigabyte_is_refused() {
+        // Some(token) with a 1 GiB length prefix and a few bytes of token.
+        let mut bytes = vec![1u8];
+        bytes.extend_from_slice(&(1u64 << 30).to_le_bytes());
+        bytes.extend_from_slice(b"aaaa");
+
+        let error = decode_client_msg(&bytes).unwrap_err();
+
+        assert!(matches!(*error, bincode::ErrorKind::SizeLimit));
+    }
+}
@@ -1,24 +1,44 @@
-use chrono::Utc;
-use futures_channel::mpsc::UnboundedSender;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use futures_channel::mpsc::Sender;
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use log::info;
-use marain_api::prelude::{ClientMsg, ClientMsgBody, ServerMsg, ServerMsgBody, Status, Timestamp};
+use marain_api::prelude::{ClientMsg, ClientMsgBody};
 
 use rand_core::OsRng;
+use sha2::{Digest, Sha256};
 
-use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::{net::TcpListener, sync::Semaphore};
+use tokio_tungstenite::{
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
+};
 
-use anyhow::{anyhow, Result};
+use crate::error::{MarainServerError, Result};
 use uuid::Uuid;
 use x25519_dalek::{PublicKey, ReusableSecret};
 
-use crate::{domain::{commands::Command, user::User}, workers::user_session::SessionWorker};
+use crate::{
+    domain::{commands::Command, notification_log::SERVER_NOTIFIER, user::User},
+    workers::user_session::SessionWorker,
+};
 
-use super::message_builder::SocketSendAdaptor;
+use super::{
+    decode::decode_client_msg,
+    message_builder::{SocketSendAdaptor, WireFormat},
+    metrics, resume,
+    transport::{ClientStream, ConnectionPermit, Listener},
+};
 
 type KeyPair = (ReusableSecret, PublicKey);
 
@@ -29,6 +49,25 @@ pub fn getenv(name: &str) -> String {
     }
 }
 
+/// Checks a requested user name against the names ordinary users may not take.
+/// Extra names can be reserved with a comma separated MARAIN_RESERVED_NAMES,
+/// the server's own notifier name is always reserved. Matching ignores case.
+pub fn is_reserved_name(name: &str) -> bool {
+    let name = name.trim().to_lowercase();
+    name == SERVER_NOTIFIER.to_lowercase()
+        || getenv("MARAIN_RESERVED_NAMES")
+            .split(',')
+            .map(|reserved| reserved.trim().to_lowercase())
+            .any(|reserved| !reserved.is_empty() && reserved == name)
+}
+
+/// A login presenting the MARAIN_ADMIN_TOKEN as its token is granted admin rights.
+/// No token grants admin rights while MARAIN_ADMIN_TOKEN is unset.
+pub fn is_admin_token(token: &str) -> bool {
+    let admin_token = getenv("MARAIN_ADMIN_TOKEN");
+    !admin_token.is_empty() && token == admin_token
+}
+
 pub fn create_key_pair() -> (ReusableSecret, PublicKey) {
     let ss = ReusableSecret::random_from_rng(OsRng);
     // let server_secret = EphemeralSecret::random_from_rng(OsRng);
@@ -36,113 +75,209 @@ pub fn create_key_pair() -> (ReusableSecret, PublicKey) {
 
     (ss, server_public)
 }
-pub async fn setup_listener() -> TcpListener {
+
+/// SHA-256 of the server public key as upper case hex in groups of four, for users
+/// to compare against a value published out of band.
+pub fn key_fingerprint(public_key: &PublicKey) -> String {
+    Sha256::digest(public_key.as_bytes())
+        .chunks(2)
+        .map(|pair| format!("{:02X}{:02X}", pair[0], pair[1]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+/// The host part of the bind address, from MARAIN_BIND_ADDR or 0.0.0.0 if unset.
+/// An address that does not parse stops the server rather than falling back to
+/// every interface, which a proxy only deployment would not expect.
+fn bind_host() -> IpAddr {
+    let host = getenv("MARAIN_BIND_ADDR");
+    if host.is_empty() {
+        return IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    }
+    match host.parse() {
+        Ok(host) => host,
+        Err(e) => {
+            log::error!("MARAIN_BIND_ADDR {host:?} is not an IP address: {e}");
+            panic!("Invalid MARAIN_BIND_ADDR");
+        }
+    }
+}
+
+pub async fn setup_listener() -> Listener {
+    #[cfg(unix)]
+    {
+        let socket_path = getenv("MARAIN_UNIX_SOCKET");
+        if !socket_path.is_empty() {
+            return setup_unix_listener(&socket_path);
+        }
+    }
+
     let mut port = getenv("MARAIN_PORT");
     if port.len() == 0 {
         port = "8080".to_string();
         log::warn!("Could not find MARAIN_PORT environment variable. Falling back to 8080.");
     }
-    let addr = format!("0.0.0.0:{}", port);
+    let addr = match bind_host() {
+        IpAddr::V6(host) => format!("[{host}]:{port}"),
+        IpAddr::V4(host) => format!("{host}:{port}"),
+    };
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
     info!("Listening on: {}", addr);
-    listener
+    #[cfg(feature = "tls")]
+    if super::tls::acceptor().is_some() {
+        info!("Terminating TLS on {}", addr);
+    }
+    Listener::Tcp(listener)
+}
+
+#[cfg(unix)]
+fn setup_unix_listener(socket_path: &str) -> Listener {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket file left behind by a previous run would make the bind fail.
+    if let Ok(metadata) = std::fs::metadata(socket_path) {
+        if metadata.file_type().is_socket() {
+            log::warn!("Removing stale unix socket at {socket_path}");
+            std::fs::remove_file(socket_path).expect("Failed to remove stale unix socket");
+        }
+    }
+    let listener = tokio::net::UnixListener::bind(socket_path).expect("Failed to bind");
+    info!("Listening on unix socket: {}", socket_path);
+    Listener::Unix(listener)
 }
 
-pub async fn handle_initial_connection(stream: TcpStream) -> SplitSocket {
-    let user_addr = stream.peer_addr().unwrap().to_string();
-    let ws_stream = tokio_tungstenite::accept_async(stream)
+/// Runs the TLS handshake, when TLS is configured, then the websocket handshake
+/// over the resulting stream.
+pub async fn handle_initial_connection(
+    stream: ClientStream,
+    user_addr: String,
+) -> Result<SplitSocket> {
+    #[cfg(feature = "tls")]
+    let stream = super::tls::accept(stream)
         .await
-        .expect("Error during the websocket handshake occurred");
+        .map_err(tokio_tungstenite::tungstenite::Error::Io)?;
+    let ws_stream = tokio_tungstenite::accept_async(stream).await?;
     info!("Websocket connection from: {}", user_addr,);
     let (ws_sink, ws_source) = ws_stream.split();
 
-    SplitSocket {
+    Ok(SplitSocket {
         sink: ws_sink,
         source: ws_source,
-    }
+    })
 }
 
 pub struct SplitSocket {
-    pub sink: SplitSink<WebSocketStream<TcpStream>, Message>,
-    pub source: SplitStream<WebSocketStream<TcpStream>>,
+    pub sink: SplitSink<WebSocketStream<ClientStream>, Message>,
+    pub source: SplitStream<WebSocketStream<ClientStream>>,
 }
 
-pub fn on_login_failed(mut socket_sink: SplitSink<WebSocketStream<TcpStream>, Message>) {
+pub fn on_login_failed(
+    mut socket_sink: SplitSink<WebSocketStream<ClientStream>, Message>,
+    format: WireFormat,
+) {
     tokio::spawn(async move {
-        let login_fail = ServerMsg {
-            status: Status::JustNo,
-            timestamp: Timestamp::from(Utc::now()),
-            body: ServerMsgBody::Empty,
-        };
-
-        socket_sink
-            .send(Message::Binary(bincode::serialize(&login_fail).unwrap()))
-            .await
-            .unwrap_or(());
+        match SocketSendAdaptor::on_login_failed(format) {
+            Ok(login_fail) => socket_sink.send(login_fail).await.unwrap_or(()),
+            Err(e) => log::error!("Could not build login failure response: {e}"),
+        }
         socket_sink.close().await.unwrap_or(());
     });
 }
 
 pub async fn on_login_success(
     user: User,
-    mut sink: SplitSink<WebSocketStream<TcpStream>, Message>,
-    source: SplitStream<WebSocketStream<TcpStream>>,
+    mut sink: SplitSink<WebSocketStream<ClientStream>, Message>,
+    source: SplitStream<WebSocketStream<ClientStream>>,
     server_public_key: PublicKey,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: Sender<Command>,
+    format: WireFormat,
 ) -> Result<SessionWorker> {
+    // The LoginSuccess token is the resume token, not the user's id.
+    let token = resume::issue(&user);
     let login_success_response =
-        SocketSendAdaptor::on_login_success(user.id.clone(), server_public_key.to_bytes())?;
+        SocketSendAdaptor::on_login_success(token, server_public_key.to_bytes(), format)
+            .inspect_err(|_| resume::revoke(&user))?;
 
-    match sink.send(login_success_response).await {
-        Err(e) => {
-            return Err(anyhow!(
-                "Failed to send successful login response: Error: {e}"
-            ));
-        }
-        _ => {}
-    };
+    if let Err(e) = sink.send(login_success_response).await {
+        log::error!("Failed to send successful login response: Error: {e}");
+        resume::revoke(&user);
+        return Err(e.into());
+    }
 
-    let session_worker = SessionWorker::new(user, gateway_sink, sink, source);
+    let session_worker = SessionWorker::new(
+        user,
+        gateway_sink,
+        sink,
+        source,
+        key_fingerprint(&server_public_key),
+    );
 
     Ok(session_worker)
 }
 
 /// handle_login_attempt consumes a deserialised login message and takes care of key shared
-/// secret management.
+/// secret management. Responses are sent in the same wire format as the login message.
 pub async fn handle_login_attempt(
     login_msg: ClientMsg,
-    socket_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
-    socket_source: SplitStream<WebSocketStream<TcpStream>>,
-    gateway_sink: UnboundedSender<Command>,
+    format: WireFormat,
+    socket_sink: SplitSink<WebSocketStream<ClientStream>, Message>,
+    socket_source: SplitStream<WebSocketStream<ClientStream>>,
+    gateway_sink: Sender<Command>,
     server_secret: ReusableSecret,
     server_public_key: PublicKey,
 ) -> Result<SessionWorker> {
     // Deserialise the initial login message from a client.
     if let ClientMsg {
-        token: None,
+        token,
         body: ClientMsgBody::Login(uname, client_public_key), // Unpack a users public key here
         ..
     } = login_msg
     {
-        let name = uname;
+        // Besides the admin token, a client may present the token from an earlier
+        // LoginSuccess to come back as the same user. One that has expired, or
+        // predates a restart, gets a fresh login instead.
+        let (is_admin, resumed) = match token {
+            None => (false, None),
+            Some(token) if is_admin_token(&token) => (true, None),
+            Some(token) => match resume::claim(&token) {
+                Some(resumed) => (resumed.is_admin, Some(resumed)),
+                None => {
+                    log::info!("Login token is not resumable, logging {uname} in afresh");
+                    (false, None)
+                }
+            },
+        };
+
+        if resumed.is_none() && is_reserved_name(&uname) && !is_admin {
+            on_login_failed(socket_sink, format);
+            return Err(MarainServerError::Auth(format!(
+                "The name {uname} is reserved"
+            )));
+        }
+
         let public_key = PublicKey::from(client_public_key);
-        let id = format!("{:X}", Uuid::new_v4().as_u128());
+        let (id, name) = match resumed {
+            Some(resumed) => (resumed.id, resumed.name),
+            None => (format!("{:X}", Uuid::new_v4().as_u128()), uname),
+        };
 
         let shared_secret = *server_secret.diffie_hellman(&public_key).as_bytes();
+        let mut user = User::new(id, name, shared_secret);
+        user.is_admin = is_admin;
 
         on_login_success(
-            User::new(id, name, shared_secret),
+            user,
             socket_sink,
             socket_source,
             server_public_key,
             gateway_sink,
+            format,
         )
         .await
     } else {
-        on_login_failed(socket_sink);
-        Err(anyhow!(
-            "Login failed: Could not deserialize login message".to_string(),
+        on_login_failed(socket_sink, format);
+        Err(MarainServerError::Auth(
+            "Expected a login message".to_string(),
         ))
     }
 }
@@ -151,60 +286,85 @@ pub async fn handle_login_attempt(
 /// does not receive a well formed initial message from the client on
 /// establishing the websocket connection.
 pub async fn handle_client_initiation(
-    mut socket_source: SplitStream<WebSocketStream<TcpStream>>,
-    sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+    mut socket_source: SplitStream<WebSocketStream<ClientStream>>,
+    sink: SplitSink<WebSocketStream<ClientStream>, Message>,
     server_secret: ReusableSecret,
     server_public_key: PublicKey,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: Sender<Command>,
 ) -> Result<SessionWorker> {
-    match socket_source.next().await {
-        Some(Ok(Message::Binary(data))) => {
-            let deserialized: ClientMsg = match bincode::deserialize(&data[..]) {
-                Ok(m) => m,
+    let deserialized = match socket_source.next().await {
+        Some(Ok(Message::Binary(data))) => match decode_client_msg(&data[..]) {
+            Ok(m) => m,
+            Err(e) => {
+                let err_msg =
+                    format!("Error during user client initiation, unrecognised message: {e}");
+                log::error!("{err_msg}");
+                return Err(MarainServerError::Serialization(err_msg));
+            }
+        },
+        // Browser clients tend to default to text frames. JSON only covers the
+        // handshake: after login a session only accepts encrypted bincode in binary
+        // frames, so a JSON login is refused, in JSON so the client can read why,
+        // rather than succeeding into a session it cannot use.
+        Some(Ok(Message::Text(text))) => {
+            let err_msg = match serde_json::from_str::<ClientMsg>(&text) {
+                Ok(_) => "Refused JSON login, sessions only accept binary frames".to_string(),
                 Err(e) => {
-                    let err_msg =
-                        format!("Error during user client initiation, unrecognised message: {e}");
-                    log::error!("{err_msg}");
-                    return Err(anyhow!("{err_msg}"));
+                    format!("Error during user client initiation, unrecognised text message: {e}")
                 }
             };
-
-            return handle_login_attempt(
-                deserialized,
-                sink,
-                socket_source,
-                gateway_sink,
-                server_secret,
-                server_public_key,
-            )
-            .await;
+            log::error!("{err_msg}");
+            on_login_failed(sink, WireFormat::Json);
+            return Err(MarainServerError::Serialization(err_msg));
         }
         _ => {
             log::error!("Could not read inbound connection from user");
-            return Err(anyhow!("Could not read inbound connection from user"));
+            return Err(MarainServerError::ChannelClosed(
+                "Could not read inbound connection from user".into(),
+            ));
         }
-    }
+    };
+
+    handle_login_attempt(
+        deserialized,
+        WireFormat::Binary,
+        sink,
+        socket_source,
+        gateway_sink,
+        server_secret,
+        server_public_key,
+    )
+    .await
 }
 
 pub async fn login_handshake(
     socket: SplitSocket,
-    gateway_sink: UnboundedSender<Command>,
+    gateway_sink: Sender<Command>,
     key_pair: KeyPair,
 ) -> Result<SessionWorker> {
     // Generate a key pair for the server
     let (server_secret, server_public) = key_pair;
     let SplitSocket { sink, source } = socket;
 
-    handle_client_initiation(source, sink, server_secret, server_public, gateway_sink).await
+    let session =
+        handle_client_initiation(source, sink, server_secret, server_public, gateway_sink).await;
+    match session {
+        Ok(_) => metrics::ACTIVE_SESSIONS.increment(),
+        Err(_) => metrics::LOGIN_FAILURES.increment(),
+    }
+    session
 }
 
 pub async fn spawn_user_session(
-    stream: TcpStream,
-    gateway_sink: UnboundedSender<Command>,
+    stream: ClientStream,
+    user_addr: String,
+    gateway_sink: Sender<Command>,
     key_pair: KeyPair,
+    permit: ConnectionPermit,
 ) -> Result<()> {
-    let split_socket = handle_initial_connection(stream).await;
+    let split_socket = handle_initial_connection(stream, user_addr).await?;
     let mut user_session = login_handshake(split_socket, gateway_sink, key_pair).await?;
+    user_session.hold_connection(permit);
     tokio::spawn(async move {
         if let Err(e) = user_session.run().await {
             log::error!("User session quit unexpectedly with error: {e}");
@@ -213,3 +373,81 @@ pub async fn spawn_user_session(
 
     Ok(())
 }
+
+const MAX_PENDING_REJECTIONS: usize = 8;
+const REJECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+static REJECTIONS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Turns away a connection while the server is at MARAIN_MAX_CONNECTIONS, closing
+/// the websocket with a try again later code so clients know to back off. Only
+/// MAX_PENDING_REJECTIONS are handled at once, each given REJECTION_TIMEOUT, and
+/// connections past that are dropped without a close frame so a flood of them
+/// costs no more than the ones already being turned away.
+pub fn reject_connection(stream: ClientStream, user_addr: String) {
+    let rejections = REJECTIONS.get_or_init(|| Semaphore::new(MAX_PENDING_REJECTIONS));
+    let Ok(permit) = rejections.try_acquire() else {
+        log::debug!("Dropping {user_addr}, too many rejections already in progress");
+        return;
+    };
+    tokio::spawn(async move {
+        let _permit = permit;
+        let rejection = send_rejection(stream, &user_addr);
+        if tokio::time::timeout(REJECTION_TIMEOUT, rejection)
+            .await
+            .is_err()
+        {
+            log::debug!("Gave up rejecting {user_addr}, the handshake took too long");
+        }
+    });
+}
+
+async fn send_rejection(stream: ClientStream, user_addr: &str) {
+    #[cfg(feature = "tls")]
+    let stream = match super::tls::accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            log::debug!("TLS handshake with rejected {user_addr} failed: {e}");
+            return;
+        }
+    };
+    let mut ws_stream = match tokio_tungstenite::accept_async(stream).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            log::debug!("Websocket handshake with rejected {user_addr} failed: {e}");
+            return;
+        }
+    };
+    let close = CloseFrame {
+        code: CloseCode::Again,
+        reason: "Server is full".into(),
+    };
+    if let Err(e) = ws_stream.close(Some(close)).await {
+        log::debug!("Could not close rejected connection from {user_addr}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::transport::ConnectionLimit;
+
+    #[tokio::test]
+    async fn connections_past_the_limit_are_closed_with_try_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let limit = ConnectionLimit::new(Some(1));
+        let _open = limit.try_acquire().unwrap();
+
+        let client = tokio::spawn(tokio_tungstenite::connect_async(url));
+        let (stream, user_addr) = listener.accept().await.unwrap();
+        assert!(limit.try_acquire().is_none());
+        reject_connection(ClientStream::Tcp(stream), user_addr.to_string());
+
+        let (mut ws_stream, _) = client.await.unwrap().unwrap();
+        match ws_stream.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Again),
+            _ => panic!("expected a close frame"),
+        }
+    }
+}
@@ -6,10 +6,51 @@ use sphinx::prelude::{cbc_encode, get_rng};
 use tokio_tungstenite::tungstenite::Message;
 
 use crate::domain::{
-    chat_log::MessageLog, notification_log::NotificationLog, room::Room, user::User,
+    chat_log::{MessageLog, ReplyTo},
+    events::{ErrorCode, RosterEntry},
+    notification_log::{NotificationLog, Severity, SERVER_NOTIFIER},
+    room::Room,
+    user::{Role, User},
 };
 
-use anyhow::{anyhow, Result};
+use crate::error::{MarainServerError, Result};
+
+/// The frame type a client used during the login handshake. Handshake responses
+/// are sent back in the same format, so a text-only client is told in JSON that
+/// its login was refused. Sessions themselves only speak encrypted bincode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Binary,
+    Json,
+}
+
+/// Why the server is closing a session, sent to the client before the socket closes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    InvalidProtocol,
+    DecryptionFailure,
+    IdleTimeout,
+    Kicked,
+    ServerShutdown,
+    /// The user logged in again with this session's resume token.
+    ResumedElsewhere,
+    Internal,
+}
+
+impl DisconnectReason {
+    /// Stable identifier clients can match on to decide whether to reconnect.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DisconnectReason::InvalidProtocol => "invalid_protocol",
+            DisconnectReason::DecryptionFailure => "decryption_failure",
+            DisconnectReason::IdleTimeout => "idle_timeout",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::ServerShutdown => "server_shutdown",
+            DisconnectReason::ResumedElsewhere => "resumed_elsewhere",
+            DisconnectReason::Internal => "internal",
+        }
+    }
+}
 
 pub struct SocketSendAdaptor;
 
@@ -18,32 +59,123 @@ impl SocketSendAdaptor {
         let serialized = match bincode::serialize(&s) {
             Ok(ser) => ser,
             Err(e) => {
-                return Err(
-                    anyhow!(
-                "Bincode::serialize failed with Error: {e:?}. Failed serializing ServerMsg: {s:?}"),
-                );
+                return Err(MarainServerError::Serialization(format!(
+                    "Bincode::serialize failed with Error: {e:?}. Failed serializing ServerMsg: {s:?}"
+                )));
             }
         };
 
         Ok(serialized)
     }
 
+    pub fn unencrypted_message(s: ServerMsg, format: WireFormat) -> Result<Message> {
+        match format {
+            WireFormat::Binary => Ok(Message::Binary(
+                SocketSendAdaptor::serialized_server_msg(s)?,
+            )),
+            WireFormat::Json => match serde_json::to_string(&s) {
+                Ok(json) => Ok(Message::Text(json)),
+                Err(e) => Err(MarainServerError::Serialization(format!(
+                    "serde_json::to_string failed with Error: {e:?}. Failed serializing ServerMsg: {s:?}"
+                ))),
+            },
+        }
+    }
+
     pub fn encrypt_message(key: &[u8; 32], serialized: Vec<u8>) -> Result<Message> {
         let rng = get_rng();
         match cbc_encode(key.to_vec(), serialized, rng) {
             Ok(enc) => Ok(Message::Binary(enc)),
-            Err(e) => Err(anyhow!("{e:?}")),
+            Err(e) => Err(MarainServerError::Encryption(format!("{e:?}"))),
         }
     }
 
-    pub fn on_login_success(token: String, public_key: [u8; 32]) -> Result<Message> {
+    pub fn on_login_success(
+        token: String,
+        public_key: [u8; 32],
+        format: WireFormat,
+    ) -> Result<Message> {
         let server_msg = ServerMsgFactory::build_login_success_server_msg(token, public_key);
+        SocketSendAdaptor::unencrypted_message(server_msg, format)
+    }
+
+    pub fn on_login_failed(format: WireFormat) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_login_failed_server_msg();
+        SocketSendAdaptor::unencrypted_message(server_msg, format)
+    }
+
+    pub fn prepare_send_msg_log(msg: MessageLog, key: &[u8; 32]) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_msg_log_server_msg(msg);
         let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
-        Ok(Message::Binary(serialized))
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
     }
 
-    pub fn prepare_send_msg_log(msg: MessageLog, user: &User, key: &[u8; 32]) -> Result<Message> {
-        let server_msg = ServerMsgFactory::build_msg_log_server_msg(msg, user);
+    pub fn user_rooms_response(key: &[u8; 32], name: &str, rooms: Vec<String>) -> Result<Message> {
+        let text = match rooms.len() {
+            0 => format!("{name} is not in any room"),
+            _ => format!("{name} is in {}", rooms.join(", ")),
+        };
+        let server_msg =
+            ServerMsgFactory::build_notification_server_msg(NotificationLog::new(text));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    /// ChatRecv has no edit marker, so the edited message is resent under its
+    /// original id with the edit marked in the text.
+    pub fn msg_edited_response(mut msg: MessageLog, key: &[u8; 32]) -> Result<Message> {
+        msg.contents = format!("[edited] {}", msg.contents);
+        SocketSendAdaptor::prepare_send_msg_log(msg, key)
+    }
+
+    pub fn mentioned_response(key: &[u8; 32], msg: &MessageLog) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notification_server_msg(NotificationLog::new(
+            format!("{} mentioned you in message {}", msg.username, msg.id),
+        ));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn typing_response(key: &[u8; 32], user: &User, is_typing: bool) -> Result<Message> {
+        let text = if is_typing {
+            format!("{} is typing", user.name)
+        } else {
+            format!("{} stopped typing", user.name)
+        };
+        let server_msg =
+            ServerMsgFactory::build_notification_server_msg(NotificationLog::new(text));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn msg_deleted_response(key: &[u8; 32], id: u64) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notification_server_msg(NotificationLog::new(
+            format!("Message {id} was deleted"),
+        ));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn single_message_response(key: &[u8; 32], message: Option<MessageLog>) -> Result<Message> {
+        let server_msg = match message {
+            Some(msg) => ServerMsgFactory::build_msg_log_server_msg(msg),
+            None => ServerMsgFactory::build_notification_server_msg(
+                NotificationLog::new("That message is not available".into())
+                    .with_severity(Severity::Warning),
+            ),
+        };
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn direct_msg_response(msg: MessageLog, key: &[u8; 32]) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_direct_msg_server_msg(msg);
         let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
         let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
         Ok(encrypted)
@@ -56,6 +188,149 @@ impl SocketSendAdaptor {
         Ok(encrypted)
     }
 
+    /// Notifications travel apart from room chat as direct messages from their
+    /// notifier, with anything above Info severity marked in the text.
+    pub fn notification_response(key: &[u8; 32], notice: NotificationLog) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notification_server_msg(notice);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn prepare_send_error(key: &[u8; 32], code: ErrorCode, message: &str) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notification_server_msg(
+            NotificationLog::new(format!("{}: {message}", code.code()))
+                .with_severity(Severity::Error),
+        );
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn join_rejected_response(key: &[u8; 32], room: &Room, reason: &str) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_join_rejected_server_msg(room, reason);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn disconnect_response(key: &[u8; 32], reason: DisconnectReason) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_disconnect_server_msg(reason);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn prepare_send_room_info(
+        key: &[u8; 32],
+        room: &Room,
+        exists: bool,
+        occupant_count: usize,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_room_info_server_msg(room, exists, occupant_count);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn recipients_response(
+        key: &[u8; 32],
+        room: Option<&Room>,
+        occupant_names: Vec<String>,
+    ) -> Result<Message> {
+        let text = match room {
+            Some(room) => format!("In {}: {}", room.name, occupant_names.join(", ")),
+            None => "You are not in a room".to_string(),
+        };
+        let server_msg =
+            ServerMsgFactory::build_notification_server_msg(NotificationLog::new(text));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    /// Room listings and prefix searches share the same rendering.
+    pub fn room_search_response(key: &[u8; 32], rooms: Vec<(String, usize)>) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_room_search_server_msg(rooms);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn roster_response(
+        key: &[u8; 32],
+        room: &Room,
+        members: Vec<RosterEntry>,
+        page: usize,
+        pages: usize,
+    ) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_roster_server_msg(room, members, page, pages);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn limits_response(
+        key: &[u8; 32],
+        max_rooms: Option<usize>,
+        max_message_bytes: usize,
+        history_window: usize,
+        features: Vec<String>,
+    ) -> Result<Message> {
+        let max_rooms = match max_rooms {
+            Some(max) => max.to_string(),
+            None => "unlimited".into(),
+        };
+        let server_msg =
+            ServerMsgFactory::build_notification_server_msg(NotificationLog::new(format!(
+                "Limits: max rooms {max_rooms}, max message {max_message_bytes} bytes, \
+                history {history_window} messages. \
+                Commands: {}",
+                features.join(", ")
+            )));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn key_fingerprint_response(key: &[u8; 32], fingerprint: &str) -> Result<Message> {
+        let server_msg = ServerMsgFactory::build_notification_server_msg(NotificationLog::new(
+            format!("Server key fingerprint (SHA-256): {fingerprint}"),
+        ));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn pong_response(
+        key: &[u8; 32],
+        server_version: &str,
+        uptime_secs: u64,
+        active_connections: usize,
+    ) -> Result<Message> {
+        let server_msg =
+            ServerMsgFactory::build_notification_server_msg(NotificationLog::new(format!(
+                "Pong: marain-server {server_version}, up {uptime_secs}s, \
+                {active_connections} connections"
+            )));
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
+    pub fn prepare_send_server_stats(
+        key: &[u8; 32],
+        connections: usize,
+        rooms: usize,
+        messages_per_min: usize,
+    ) -> Result<Message> {
+        let server_msg =
+            ServerMsgFactory::build_server_stats_server_msg(connections, rooms, messages_per_min);
+        let serialized = SocketSendAdaptor::serialized_server_msg(server_msg)?;
+        let encrypted = SocketSendAdaptor::encrypt_message(key, serialized)?;
+        Ok(encrypted)
+    }
+
     pub fn room_data_response(
         key: &[u8; 32],
         chat_logs: Vec<MessageLog>,
@@ -82,6 +357,14 @@ impl ServerMsgFactory {
         }
     }
 
+    fn build_login_failed_server_msg() -> ServerMsg {
+        ServerMsg {
+            status: Status::JustNo,
+            timestamp: Timestamp::from(Utc::now()),
+            body: ServerMsgBody::Empty,
+        }
+    }
+
     fn build_room_data(
         chat_logs: Vec<MessageLog>,
         notifications: Vec<NotificationLog>,
@@ -96,16 +379,12 @@ impl ServerMsgFactory {
                 room_name: room.name.clone(),
                 logs: chat_logs
                     .iter()
-                    .map(|ml| ChatMsg {
-                        sender: ml.username.clone(),
-                        timestamp: Timestamp::from(ml.timestamp),
-                        content: ml.contents.clone(),
-                    })
+                    .map(ServerMsgFactory::build_chat_msg)
                     .collect(),
                 notifications: notifications
                     .iter()
                     .map(|nl| Notification {
-                        sender: "SERVER".into(),
+                        sender: SERVER_NOTIFIER.into(),
                         timestamp: Timestamp::from(nl.timestamp),
                         content: nl.contents.clone(),
                     })
@@ -115,21 +394,148 @@ impl ServerMsgFactory {
         }
     }
 
-    fn build_msg_log_server_msg(msg: MessageLog, user: &User) -> ServerMsg {
+    /// The envelope carries the time the server sent the message, while the chat
+    /// message keeps the server assigned time it was recorded at.
+    fn build_msg_log_server_msg(msg: MessageLog) -> ServerMsg {
         ServerMsg {
             status: Status::Yes,
-            timestamp: msg.timestamp.into(),
+            timestamp: Timestamp::from(Utc::now()),
             body: ServerMsgBody::ChatRecv {
                 direct: false,
+                chat_msg: ServerMsgFactory::build_chat_msg(&msg),
+            },
+        }
+    }
+
+    fn build_direct_msg_server_msg(msg: MessageLog) -> ServerMsg {
+        ServerMsg {
+            status: Status::Yes,
+            timestamp: Timestamp::from(Utc::now()),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
+                chat_msg: ServerMsgFactory::build_chat_msg(&msg),
+            },
+        }
+    }
+
+    /// ChatMsg has no id or reply fields, so the message id and any parent
+    /// reference are rendered into the text for clients to quote back.
+    fn build_chat_msg(msg: &MessageLog) -> ChatMsg {
+        let content = match msg.reply_to {
+            None => msg.contents.clone(),
+            Some(ReplyTo {
+                id,
+                available: true,
+            }) => format!("[reply to {id}] {}", msg.contents),
+            Some(ReplyTo {
+                id,
+                available: false,
+            }) => format!("[reply to {id}, unavailable] {}", msg.contents),
+        };
+        let content = format!("[#{}] {content}", msg.id);
+        ChatMsg {
+            sender: msg.username.clone(),
+            timestamp: Timestamp::from(msg.timestamp),
+            content,
+        }
+    }
+
+    fn build_notification_server_msg(notice: NotificationLog) -> ServerMsg {
+        let content = match notice.severity {
+            Severity::Info => notice.contents,
+            Severity::Warning => format!("[warning] {}", notice.contents),
+            Severity::Error => format!("[error] {}", notice.contents),
+        };
+        ServerMsg {
+            status: match notice.severity {
+                Severity::Error => Status::JustNo,
+                _ => Status::Yes,
+            },
+            timestamp: Timestamp::from(Utc::now()),
+            body: ServerMsgBody::ChatRecv {
+                direct: true,
                 chat_msg: ChatMsg {
-                    sender: user.name.clone(),
-                    timestamp: msg.timestamp.into(),
-                    content: msg.contents.clone(),
+                    sender: notice.notifier,
+                    timestamp: notice.timestamp.into(),
+                    content,
                 },
             },
         }
     }
 
+    fn build_join_rejected_server_msg(room: &Room, reason: &str) -> ServerMsg {
+        ServerMsgFactory::build_notification_server_msg(
+            NotificationLog::new(format!("Could not join {}: {reason}", room.name))
+                .with_severity(Severity::Error),
+        )
+    }
+
+    fn build_disconnect_server_msg(reason: DisconnectReason) -> ServerMsg {
+        ServerMsgFactory::build_notification_server_msg(
+            NotificationLog::new(format!("disconnect: {}", reason.code()))
+                .with_severity(Severity::Error),
+        )
+    }
+
+    fn build_room_info_server_msg(room: &Room, exists: bool, occupant_count: usize) -> ServerMsg {
+        let text = if exists {
+            format!("{} exists with {occupant_count} occupants", room.name)
+        } else {
+            format!("{} does not exist", room.name)
+        };
+        ServerMsgFactory::build_notification_server_msg(NotificationLog::new(text))
+    }
+
+    fn build_room_search_server_msg(rooms: Vec<(String, usize)>) -> ServerMsg {
+        let text = if rooms.is_empty() {
+            "No rooms found".to_string()
+        } else {
+            let rooms: Vec<String> = rooms
+                .iter()
+                .map(|(name, occupants)| format!("{name} ({occupants})"))
+                .collect();
+            format!("Rooms: {}", rooms.join(", "))
+        };
+        ServerMsgFactory::build_notification_server_msg(NotificationLog::new(text))
+    }
+
+    fn build_roster_server_msg(
+        room: &Room,
+        members: Vec<RosterEntry>,
+        page: usize,
+        pages: usize,
+    ) -> ServerMsg {
+        let members: Vec<String> = members
+            .iter()
+            .map(|member| {
+                let role = match member.role {
+                    Role::Member => "member",
+                    Role::Admin => "admin",
+                };
+                format!(
+                    "{} ({role}, joined {})",
+                    member.name,
+                    member.joined_at.format("%H-%M-%S")
+                )
+            })
+            .collect();
+        ServerMsgFactory::build_notification_server_msg(NotificationLog::new(format!(
+            "{} roster, page {page} of {pages}: {}",
+            room.name,
+            members.join(", ")
+        )))
+    }
+
+    fn build_server_stats_server_msg(
+        connections: usize,
+        rooms: usize,
+        messages_per_min: usize,
+    ) -> ServerMsg {
+        ServerMsgFactory::build_notification_server_msg(NotificationLog::new(format!(
+            "Server stats: {connections} connections, {rooms} rooms, {messages_per_min} messages/min"
+        )))
+    }
+
     fn build_time_server_msg(time: Timestamp) -> ServerMsg {
         ServerMsg {
             status: Status::Yes,
@@ -138,3 +544,29 @@ impl ServerMsgFactory {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids_reach_live_messages_and_snapshots() {
+        let alice = User::new("alice".into(), "alice".into(), [0; 32]);
+        let msg = MessageLog::from_user(&alice, "hello".into());
+        let expected = format!("[#{}] hello", msg.id);
+
+        let ServerMsgBody::ChatRecv { chat_msg, .. } =
+            ServerMsgFactory::build_msg_log_server_msg(msg.clone()).body
+        else {
+            panic!("expected ChatRecv");
+        };
+        assert_eq!(chat_msg.content, expected);
+
+        let ServerMsgBody::RoomData { logs, .. } =
+            ServerMsgFactory::build_room_data(vec![msg], vec![], vec![], &Room::default()).body
+        else {
+            panic!("expected RoomData");
+        };
+        assert_eq!(logs[0].content, expected);
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A process wide value reported on the metrics endpoint.
+pub struct Metric {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    value: AtomicU64,
+}
+
+impl Metric {
+    const fn counter(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: "counter",
+            value: AtomicU64::new(0),
+        }
+    }
+
+    const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: "gauge",
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn increment(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn decrement(&self) {
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                Some(value.saturating_sub(1))
+            });
+    }
+
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+pub static ACTIVE_SESSIONS: Metric = Metric::gauge(
+    "marain_active_sessions",
+    "Sessions that completed login and have not ended",
+);
+pub static COMMANDS_HANDLED: Metric = Metric::counter(
+    "marain_commands_handled_total",
+    "Commands processed by the App",
+);
+pub static BROADCASTS_PUBLISHED: Metric = Metric::counter(
+    "marain_broadcasts_published_total",
+    "Broadcasts published by the event bus",
+);
+pub static ROOMS: Metric = Metric::gauge("marain_rooms", "Rooms currently known to the App");
+pub static LOGIN_FAILURES: Metric = Metric::counter(
+    "marain_login_failures_total",
+    "Connections that failed the login handshake",
+);
+
+const ALL: [&Metric; 5] = [
+    &ACTIVE_SESSIONS,
+    &COMMANDS_HANDLED,
+    &BROADCASTS_PUBLISHED,
+    &ROOMS,
+    &LOGIN_FAILURES,
+];
+
+/// Every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    ALL.iter()
+        .map(|metric| {
+            format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+                name = metric.name,
+                help = metric.help,
+                kind = metric.kind,
+                value = metric.get(),
+            )
+        })
+        .collect()
+}
+
+/// Serves render on GET /metrics until the listener fails. Anything else gets a 404.
+#[cfg(feature = "metrics")]
+pub async fn serve(port: u16) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    log::info!("Serving metrics on port {port}");
+    loop {
+        let (mut stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::warn!("Failed to accept a metrics connection: {e}");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            let mut request = [0u8; 1024];
+            let read = stream.read(&mut request).await.unwrap_or(0);
+            let request_line = String::from_utf8_lossy(&request[..read]);
+            let response = match request_line.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                ["GET", "/metrics"] => {
+                    let body = render();
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                log::debug!("Could not send metrics response: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_reports_every_metric_with_its_type() {
+        let rendered = render();
+
+        for metric in ALL {
+            assert!(rendered.contains(&format!("# TYPE {} {}\n", metric.name, metric.kind)));
+        }
+    }
+
+    #[test]
+    fn gauges_do_not_go_below_zero() {
+        let gauge = Metric::gauge("test_gauge", "");
+        gauge.decrement();
+        assert_eq!(gauge.get(), 0);
+        gauge.increment();
+        gauge.set(4);
+        gauge.decrement();
+        assert_eq!(gauge.get(), 3);
+    }
+}
@@ -1,2 +1,9 @@
+pub mod command_parser;
+pub mod decode;
 pub mod login;
-pub mod message_builder;
\ No newline at end of file
+pub mod message_builder;
+pub mod metrics;
+pub mod resume;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
\ No newline at end of file
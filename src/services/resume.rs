@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+use uuid::Uuid;
+
+use crate::domain::user::User;
+
+use super::login::getenv;
+
+/// How long a dropped session can be resumed for, unless MARAIN_RESUME_TTL_SECS
+/// says otherwise. Long enough to ride out a mobile network handover.
+pub const DEFAULT_RESUME_TTL: Duration = Duration::from_secs(120);
+
+pub fn resume_ttl() -> Duration {
+    match getenv("MARAIN_RESUME_TTL_SECS").parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        _ => DEFAULT_RESUME_TTL,
+    }
+}
+
+/// Who a resume token logs back in as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resumable {
+    pub id: String,
+    pub name: String,
+    pub is_admin: bool,
+    expires_at: Instant,
+    /// Whether the session the token was issued to is still running.
+    live: bool,
+}
+
+lazy_static! {
+    /// Keyed by token.
+    static ref RESUMABLE: Mutex<HashMap<String, Resumable>> = Mutex::new(HashMap::new());
+}
+
+/// Hands out the token sent to the client as its LoginSuccess token. It is random
+/// rather than the user's id, so knowing who someone is does not let you log in
+/// as them, and it is good for resume_ttl from now.
+pub fn issue(user: &User) -> String {
+    let token = format!("{:X}", Uuid::new_v4().as_u128());
+    RESUMABLE.lock().unwrap().insert(
+        token.clone(),
+        Resumable {
+            id: user.id.clone(),
+            name: user.name.clone(),
+            is_admin: user.is_admin,
+            expires_at: Instant::now() + resume_ttl(),
+            live: true,
+        },
+    );
+    token
+}
+
+/// Restarts the resume window for a session that has ended, under the name it
+/// ended with. Does nothing if the token was revoked or already claimed.
+pub fn release(user: &User) {
+    let expires_at = Instant::now() + resume_ttl();
+    for resumable in RESUMABLE.lock().unwrap().values_mut() {
+        if resumable.id == user.id {
+            resumable.name = user.name.clone();
+            resumable.expires_at = expires_at;
+            resumable.live = false;
+        }
+    }
+}
+
+/// For sessions that must not be resumed, such as a kicked user's.
+pub fn revoke(user: &User) {
+    RESUMABLE
+        .lock()
+        .unwrap()
+        .retain(|_, resumable| resumable.id != user.id);
+}
+
+/// Spends a resume token. Each token logs in once, the new session is issued a
+/// fresh one on success.
+///
+/// A token can be claimed while its session is still running, for resume_ttl
+/// after it was issued, so a client can get back in before the server notices
+/// its old connection is gone. The login then takes that session over and the
+/// old connection is closed with ResumedElsewhere. Past the window a live
+/// session cannot be taken over, its token only becomes claimable again for
+/// resume_ttl once the session ends.
+pub fn claim(token: &str) -> Option<Resumable> {
+    let mut resumable = RESUMABLE.lock().unwrap();
+    let now = Instant::now();
+    resumable.retain(|_, entry| entry.live || entry.expires_at > now);
+    match resumable.get(token) {
+        Some(entry) if entry.expires_at > now => resumable.remove(token),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str) -> User {
+        User::new(id.into(), "alice".into(), [0; 32])
+    }
+
+    #[test]
+    fn tokens_can_only_be_claimed_once() {
+        let alice = user("resume-once");
+        let token = issue(&alice);
+
+        let resumed = claim(&token).unwrap();
+
+        assert_eq!(resumed.id, alice.id);
+        assert_eq!(claim(&token), None);
+    }
+
+    #[test]
+    fn user_ids_are_not_tokens() {
+        let alice = user("resume-by-id");
+        issue(&alice);
+
+        assert_eq!(claim(&alice.id), None);
+    }
+
+    #[test]
+    fn live_sessions_are_only_claimable_until_the_window_closes() {
+        let alice = user("resume-expired");
+        let token = issue(&alice);
+        RESUMABLE
+            .lock()
+            .unwrap()
+            .get_mut(&token)
+            .unwrap()
+            .expires_at = Instant::now();
+
+        assert_eq!(claim(&token), None);
+
+        // Ending the session opens a fresh window.
+        release(&alice);
+        assert_eq!(claim(&token).unwrap().id, alice.id);
+    }
+
+    #[test]
+    fn released_sessions_resume_under_their_latest_name() {
+        let mut alice = user("resume-renamed");
+        let token = issue(&alice);
+        alice.name = "alicia".into();
+
+        release(&alice);
+
+        assert_eq!(claim(&token).unwrap().name, "alicia");
+    }
+
+    #[test]
+    fn revoked_and_unknown_tokens_are_refused() {
+        let alice = user("resume-revoked");
+        let token = issue(&alice);
+
+        revoke(&alice);
+        release(&alice);
+
+        assert_eq!(claim(&token), None);
+        assert_eq!(claim("never-issued"), None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    sync::{Arc, OnceLock},
+};
+
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+use super::{login::getenv, transport::ClientStream};
+
+static ACCEPTOR: OnceLock<Option<TlsAcceptor>> = OnceLock::new();
+
+/// The acceptor for the PEM files named by MARAIN_TLS_CERT and MARAIN_TLS_KEY, or
+/// None when either is unset and connections stay plain TCP. Loaded on first use,
+/// which setup_listener makes sure is at startup, and a bad certificate or key
+/// stops the server there rather than failing every handshake later.
+pub fn acceptor() -> Option<&'static TlsAcceptor> {
+    ACCEPTOR
+        .get_or_init(|| {
+            let (cert_path, key_path) = (getenv("MARAIN_TLS_CERT"), getenv("MARAIN_TLS_KEY"));
+            if cert_path.is_empty() || key_path.is_empty() {
+                return None;
+            }
+            match load_acceptor(&cert_path, &key_path) {
+                Ok(acceptor) => Some(acceptor),
+                Err(e) => {
+                    log::error!(
+                        "Could not load TLS certificate {cert_path} and key {key_path}: {e}"
+                    );
+                    panic!("Invalid TLS configuration");
+                }
+            }
+        })
+        .as_ref()
+}
+
+fn load_acceptor(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<io::Result<Vec<_>>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no private key found"))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Runs the TLS handshake on a TCP connection when TLS is configured. Unix socket
+/// connections are local, so they stay plain either way.
+pub async fn accept(stream: ClientStream) -> io::Result<ClientStream> {
+    match (acceptor(), stream) {
+        (Some(acceptor), ClientStream::Tcp(tcp)) => {
+            Ok(ClientStream::Tls(Box::new(acceptor.accept(tcp).await?)))
+        }
+        (_, stream) => Ok(stream),
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+};
+
+/// The socket the server accepts client connections on. TCP is the default,
+/// a unix domain socket can be used when running behind a local proxy.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Accepts the next connection along with a printable peer address.
+    pub async fn accept(&self) -> io::Result<(ClientStream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((ClientStream::Tcp(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                // Unix socket peers are usually unnamed, so there is often nothing to show.
+                let addr = match addr.as_pathname() {
+                    Some(path) => path.display().to_string(),
+                    None => "unnamed unix socket peer".to_string(),
+                };
+                Ok((ClientStream::Unix(stream), addr))
+            }
+        }
+    }
+}
+
+/// How the accept loop should react to a failed accept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptFailure {
+    /// Only the connection being accepted failed, keep accepting straight away.
+    Connection,
+    /// The process is likely out of file descriptors or memory, wait before retrying.
+    Exhausted,
+    /// The listener itself is unusable.
+    Fatal,
+}
+
+impl AcceptFailure {
+    pub fn classify(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut => AcceptFailure::Connection,
+            io::ErrorKind::InvalidInput
+            | io::ErrorKind::PermissionDenied
+            | io::ErrorKind::Unsupported => AcceptFailure::Fatal,
+            _ => AcceptFailure::Exhausted,
+        }
+    }
+}
+
+/// Counts open client connections against MARAIN_MAX_CONNECTIONS. With no
+/// maximum every connection is let in, but they are still counted.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        Self {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// A permit for one more connection, or None when the server is full.
+    pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+        let admitted =
+            self.open
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| match self.max {
+                    Some(max) if open >= max => None,
+                    _ => Some(open + 1),
+                });
+        admitted.ok().map(|_| ConnectionPermit {
+            open: self.open.clone(),
+        })
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.open.load(Ordering::Acquire)
+    }
+}
+
+/// Holds a place under the ConnectionLimit until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// A connected client socket of whichever kind the listener accepted.
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// Boxed since the TLS session state is large.
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connections_past_the_maximum_are_refused() {
+        let limit = ConnectionLimit::new(Some(2));
+        let first = limit.try_acquire().unwrap();
+        let _second = limit.try_acquire().unwrap();
+
+        assert!(limit.try_acquire().is_none());
+        assert_eq!(limit.open_connections(), 2);
+
+        drop(first);
+        assert!(limit.try_acquire().is_some());
+    }
+
+    #[test]
+    fn no_maximum_admits_everyone() {
+        let limit = ConnectionLimit::new(None);
+        let permits: Vec<ConnectionPermit> = (0..100).filter_map(|_| limit.try_acquire()).collect();
+
+        assert_eq!(permits.len(), 100);
+    }
+}
@@ -1,52 +1,109 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use chrono::{DateTime, Duration, Utc};
+use futures_channel::mpsc::{unbounded, Receiver, UnboundedReceiver, UnboundedSender};
 use futures_util::StreamExt;
+use tokio::time::{interval, Instant, Interval};
 
 use crate::domain::{
-    chat_log::MessageLog,
-    commands::{Command, CommandPayload},
-    events::Event,
-    notification_log::NotificationLog,
+    chat_log::{sanitize_contents, MessageLog, ReplyTo},
+    commands::{Command, CommandPayload, CLIENT_COMMAND_KINDS},
+    events::{ErrorCode, Event, RosterEntry},
+    notification_log::{NotificationLog, Severity},
+    permissions::Permissions,
     room::Room,
     user::User,
 };
+use crate::error::MarainServerError;
+use crate::services::command_parser;
+use crate::services::login::{getenv, is_reserved_name};
+use crate::services::{metrics, resume};
 
 use anyhow::{anyhow, Result};
 
+const ROSTER_PAGE_SIZE: usize = 50;
+const MAX_ROOM_SEARCH_RESULTS: usize = 20;
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 4096;
+const DEFAULT_MAX_STORED_PAGES: usize = 1000;
+const MESSAGE_EXPIRY_SWEEP: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Fills in the {name}, {room} and {occupant_count} tokens of a join banner.
+/// The count includes the joining user. Any other braces are left as written.
+fn render_banner(template: &str, user: &User, room: &Room, occupant_count: usize) -> String {
+    template
+        .replace("{name}", &user.name)
+        .replace("{room}", &room.name)
+        .replace("{occupant_count}", &occupant_count.to_string())
+}
+
 struct EventBus {
-    subscribers: HashMap<User, UnboundedSender<Event>>,
+    /// Each subscriber's event channel and the generation it registered with.
+    subscribers: HashMap<User, (UnboundedSender<Event>, u64)>,
+    /// Subscribers whose channel was found closed, waiting to be dropped by the App.
+    dead: Vec<User>,
 }
 
 impl EventBus {
     fn new() -> Self {
         Self {
             subscribers: HashMap::new(),
+            dead: vec![],
         }
     }
 
+    /// Delivers the event to each subscriber. A closed channel means the session
+    /// went away without dropping the user, so it is logged and the user is
+    /// recorded in dead for the App to clean up.
     pub fn publish(&mut self, broadcast: &Broadcast) {
+        // Re-insert so the stored key carries the new name as well as the id.
+        if let Event::Renamed { old, new } = &broadcast.event {
+            if let Some(channel) = self.subscribers.remove(old) {
+                self.subscribers.insert(new.clone(), channel);
+            }
+        }
+        metrics::BROADCASTS_PUBLISHED.increment();
         for user in &broadcast.subscribers {
-            if let Some(channel) = self.subscribers.get(user) {
-                channel.unbounded_send(broadcast.event.clone()).unwrap();
+            if let Some((channel, _)) = self.subscribers.get(user) {
+                if let Err(e) = channel.unbounded_send(broadcast.event.clone()) {
+                    log::warn!("Could not deliver event to {user:?}, dropping them. Error: {e}");
+                    if !self.dead.contains(user) {
+                        self.dead.push(user.clone());
+                    }
+                }
             }
         }
     }
 
+    /// Subscribing a user who is already subscribed replaces their old channel, so
+    /// a reconnecting client takes over the subscription of its stale session. The
+    /// old entry is removed first, insert alone would keep the stale key and with
+    /// it the old name and is_admin.
     pub fn subscribe(
         &mut self,
         user: User,
         delivery_channel: UnboundedSender<Event>,
-    ) -> Result<()> {
-        match self.subscribers.insert(user, delivery_channel) {
-            Some(_) => Err(anyhow!("We got a double subscription chief")),
-            None => Ok(()),
+        generation: u64,
+    ) {
+        if let Some((stale, _)) = self.subscribers.remove(&user) {
+            log::warn!("{user:?} subscribed twice, replacing their old channel");
+            stale.close_channel();
         }
+        self.subscribers
+            .insert(user, (delivery_channel, generation));
     }
 
-    pub fn unsubscribe(&mut self, user: User) -> Result<()> {
+    /// Whether the user is subscribed from a session other than the one with this
+    /// generation, meaning that session has been taken over.
+    fn superseded(&self, user: &User, generation: u64) -> bool {
+        self.subscribers
+            .get(user)
+            .is_some_and(|(_, current)| *current != generation)
+    }
+
+    /// Returns the generation the user was subscribed with.
+    pub fn unsubscribe(&mut self, user: User) -> Result<u64> {
         match self.subscribers.remove(&user) {
-            Some(_) => Ok(()),
+            Some((_, generation)) => Ok(generation),
             None => Err(anyhow!(
                 "Tried to unsubscribe a user that was not subscribed."
             )),
@@ -69,7 +126,62 @@ struct AppState {
     occupancy: HashMap<Room, Vec<User>>,
     chat_logs: HashMap<Room, VecDeque<MessageLog>>,
     notifications: HashMap<Room, VecDeque<NotificationLog>>,
+    /// Pages waiting for users who left and can still resume, keyed by User.id.
+    pages: HashMap<String, VecDeque<NotificationLog>>,
+    /// Whose pages each user drops, both sides keyed by User.id.
+    ignored: HashMap<String, HashSet<String>>,
+    joined_at: HashMap<User, DateTime<Utc>>,
+    room_creators: HashMap<Room, String>,
+    message_ttls: HashMap<Room, Duration>,
+    /// The longest message ttl an admin may set, from MARAIN_MAX_MESSAGE_TTL_SECS.
+    max_message_ttl: Duration,
+    capacities: HashMap<Room, usize>,
+    /// Users queued for full rooms, in the order they asked.
+    waitlists: HashMap<Room, VecDeque<User>>,
+    history_limits: HashMap<Room, usize>,
+    /// Rooms where only admins may post, everyone else can still read and move.
+    read_only_rooms: HashSet<Room>,
+    previous_rooms: HashMap<User, Room>,
+    /// Where users were and when they left, so a resumed session can go back.
+    departed: HashMap<User, (Room, DateTime<Utc>)>,
+    resume_ttl: Duration,
+    /// Users whose session ended but who still hold their place in a room, and when
+    /// the session ended. The sweep removes them once occupancy_grace has passed.
+    disconnected: HashMap<User, DateTime<Utc>>,
+    /// How long a dropped user keeps their place, from MARAIN_OCCUPANCY_GRACE_SECS.
+    /// Zero removes them straight away.
+    occupancy_grace: Duration,
+    banners: Vec<String>,
+    next_banner: usize,
+    permissions: Permissions,
+    recent_messages: VecDeque<DateTime<Utc>>,
+    /// When each user's recent chat messages were sent, keyed by User.id.
+    sent_messages: HashMap<String, VecDeque<DateTime<Utc>>>,
+    rate_limit: Option<usize>,
+    rate_limit_window: Duration,
+    /// When each user's last typing update was passed on and what it said, keyed by
+    /// User.id.
+    last_typing: HashMap<String, (DateTime<Utc>, bool)>,
+    typing_interval: Duration,
+    /// Messages pushed out of a room's history, waiting to be announced to the room.
+    evicted: Vec<(Room, u64)>,
+    announce_evictions: bool,
     max_logs: usize,
+    max_pages: usize,
+    /// Pages held across everyone, from MARAIN_MAX_STORED_PAGES.
+    max_stored_pages: usize,
+    page_ttl: Duration,
+    max_rooms_per_user: Option<usize>,
+    /// Ceiling on the number of rooms, the Hub included, from MARAIN_MAX_ROOMS.
+    max_rooms: Option<usize>,
+    /// Rooms made with CreateRoom, which are kept when they empty out. Rooms made
+    /// by moving into them are dropped once the last occupant leaves, if room
+    /// collection is on.
+    created_rooms: HashSet<Room>,
+    /// Set by MARAIN_GC_EMPTY_ROOMS. Collection is also on whenever max_rooms is
+    /// set, since the cap would otherwise fill up with abandoned rooms for good.
+    gc_empty_rooms: bool,
+    max_message_bytes: usize,
 }
 
 impl AppState {
@@ -78,10 +190,110 @@ impl AppState {
             occupancy: HashMap::from([(Room::default(), vec![])]),
             chat_logs: HashMap::from([(Room::default(), VecDeque::new())]),
             notifications: HashMap::from([(Room::default(), VecDeque::new())]),
+            pages: HashMap::new(),
+            ignored: HashMap::new(),
+            joined_at: HashMap::new(),
+            room_creators: HashMap::new(),
+            message_ttls: HashMap::new(),
+            max_message_ttl: getenv("MARAIN_MAX_MESSAGE_TTL_SECS")
+                .parse()
+                .ok()
+                .and_then(Duration::try_seconds)
+                .unwrap_or_else(|| Duration::days(30)),
+            capacities: HashMap::new(),
+            waitlists: HashMap::new(),
+            history_limits: AppState::load_history_limits(),
+            read_only_rooms: HashSet::new(),
+            previous_rooms: HashMap::new(),
+            departed: HashMap::new(),
+            resume_ttl: Duration::from_std(resume::resume_ttl()).unwrap_or(Duration::zero()),
+            disconnected: HashMap::new(),
+            occupancy_grace: getenv("MARAIN_OCCUPANCY_GRACE_SECS")
+                .parse()
+                .ok()
+                .and_then(Duration::try_seconds)
+                .unwrap_or(Duration::zero()),
+            banners: AppState::load_banners(),
+            next_banner: 0,
+            permissions: AppState::load_permissions(),
+            recent_messages: VecDeque::new(),
+            sent_messages: HashMap::new(),
+            rate_limit: getenv("MARAIN_RATE_LIMIT").parse().ok(),
+            rate_limit_window: Duration::seconds(10),
+            last_typing: HashMap::new(),
+            typing_interval: Duration::seconds(2),
+            evicted: vec![],
+            announce_evictions: getenv("MARAIN_ANNOUNCE_EVICTIONS") == "true",
             max_logs: 25,
+            max_pages: 10,
+            max_stored_pages: getenv("MARAIN_MAX_STORED_PAGES")
+                .parse()
+                .unwrap_or(DEFAULT_MAX_STORED_PAGES),
+            page_ttl: Duration::hours(24),
+            max_rooms_per_user: getenv("MARAIN_MAX_ROOMS_PER_USER").parse().ok(),
+            max_rooms: getenv("MARAIN_MAX_ROOMS").parse().ok(),
+            created_rooms: HashSet::new(),
+            gc_empty_rooms: getenv("MARAIN_GC_EMPTY_ROOMS") == "true",
+            max_message_bytes: getenv("MARAIN_MAX_MSG_BYTES")
+                .parse()
+                .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES),
+        }
+    }
+
+    /// The Hub's history limit can be set with MARAIN_HUB_HISTORY, other rooms start
+    /// at the default max_logs until an admin changes them.
+    fn load_history_limits() -> HashMap<Room, usize> {
+        match getenv("MARAIN_HUB_HISTORY").parse() {
+            Ok(limit) => HashMap::from([(Room::default(), limit)]),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    /// How many chat messages and notifications the room keeps.
+    fn history_limit(&self, room: &Room) -> usize {
+        *self.history_limits.get(room).unwrap_or(&self.max_logs)
+    }
+
+    /// Reads join banners from the file named by MARAIN_BANNERS, one per line.
+    fn load_banners() -> Vec<String> {
+        let path = getenv("MARAIN_BANNERS");
+        if path.is_empty() {
+            return vec![];
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => contents
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(String::from)
+                .collect(),
+            Err(e) => {
+                log::warn!("Could not read banners from {path}, none will be shown. Error: {e}");
+                vec![]
+            }
         }
     }
 
+    /// Reads the command permission matrix from the file named by MARAIN_PERMISSIONS.
+    /// A file that cannot be read is fatal rather than silently allowing everything.
+    fn load_permissions() -> Permissions {
+        let path = getenv("MARAIN_PERMISSIONS");
+        if path.is_empty() {
+            return Permissions::default();
+        }
+        let contents = std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Could not read permissions from {path}. Error: {e}"));
+        Permissions::from_toml(&contents)
+            .unwrap_or_else(|e| panic!("Invalid permissions in {path}. Error: {e}"))
+    }
+
+    /// The next banner in rotation, if any are configured.
+    fn next_banner(&mut self) -> Option<String> {
+        let banner = self.banners.get(self.next_banner)?.clone();
+        self.next_banner = (self.next_banner + 1) % self.banners.len();
+        Some(banner)
+    }
+
     fn room_subscribers(&self, room: &Room) -> Vec<User> {
         self.occupancy.get(room).unwrap_or(&vec![]).clone()
     }
@@ -104,6 +316,57 @@ impl AppState {
             .collect()
     }
 
+    /// The most recent notifications across every room, oldest first.
+    fn latest_global_notifications(&self, limit: usize) -> Vec<NotificationLog> {
+        let mut notifications: Vec<NotificationLog> =
+            self.notifications.values().flatten().cloned().collect();
+        notifications.sort_by_key(|notice| notice.timestamp);
+        let skip = notifications.len().saturating_sub(limit);
+        notifications.into_iter().skip(skip).collect()
+    }
+
+    /// Whether the room has reached its capacity. The Hub is never full, so there is
+    /// always somewhere to go.
+    fn room_is_full(&self, room: &Room) -> bool {
+        if *room == Room::default() {
+            return false;
+        }
+        match self.capacities.get(room) {
+            Some(capacity) => self.room_subscribers(room).len() >= *capacity,
+            None => false,
+        }
+    }
+
+    /// Every room with its occupant count, Hub first and the rest sorted by name.
+    fn list_rooms(&self) -> Vec<(String, usize)> {
+        let hub = Room::default();
+        let mut rooms: Vec<(String, usize)> = self
+            .occupancy
+            .iter()
+            .filter(|(room, _)| **room != hub)
+            .map(|(room, occupants)| (room.name.clone(), occupants.len()))
+            .collect();
+        rooms.sort();
+        rooms.insert(0, (hub.name.clone(), self.room_subscribers(&hub).len()));
+        rooms
+    }
+
+    /// Rooms whose names start with the prefix, ignoring case, sorted by name.
+    fn search_rooms(&self, prefix: &str, limit: usize) -> Vec<(String, usize)> {
+        let prefix = prefix.to_lowercase();
+        let mut rooms: Vec<(String, usize)> = self
+            .occupancy
+            .iter()
+            .filter(|(room, _)| room.name.to_lowercase().starts_with(&prefix))
+            .map(|(room, occupants)| (room.name.clone(), occupants.len()))
+            .collect();
+        rooms.sort();
+        rooms.truncate(limit.min(MAX_ROOM_SEARCH_RESULTS));
+        rooms
+    }
+
+    /// Names in the order their owners joined the room, which clients rely on to
+    /// keep their occupant lists from reshuffling.
     fn occupant_names(&self, room: &Room) -> Vec<String> {
         self.room_subscribers(&room)
             .iter()
@@ -111,11 +374,283 @@ impl AppState {
             .collect()
     }
 
-    fn add_user_to_room(&mut self, user: &User, room: &Room) {
+    fn roster(&self, room: &Room) -> Vec<RosterEntry> {
+        self.room_subscribers(room)
+            .iter()
+            .map(|occupant| RosterEntry {
+                name: occupant.name.clone(),
+                role: occupant.role(),
+                joined_at: self
+                    .joined_at
+                    .get(occupant)
+                    .cloned()
+                    .unwrap_or_else(Utc::now),
+            })
+            .collect()
+    }
+
+    /// Rooms are created by moving into them, the creator is remembered so the
+    /// number of live rooms each user has created can be capped.
+    fn can_create_room(&self, user: &User) -> Result<(), String> {
+        if let Some(max_rooms) = self.max_rooms {
+            if self.occupancy.len() >= max_rooms {
+                return Err(format!(
+                    "The server already has its limit of {max_rooms} rooms"
+                ));
+            }
+        }
+        let Some(max_rooms) = self.max_rooms_per_user else {
+            return Ok(());
+        };
+        let created = self
+            .room_creators
+            .values()
+            .filter(|creator| **creator == user.id)
+            .count();
+        if created >= max_rooms {
+            return Err(format!("You may only create {max_rooms} rooms"));
+        }
+        Ok(())
+    }
+
+    fn find_user_by_name(&self, name: &str) -> Option<User> {
         self.occupancy
-            .entry(room.clone())
-            .and_modify(|members| members.push(user.clone()))
-            .or_insert(vec![user.clone()]);
+            .values()
+            .flatten()
+            .find(|occupant| occupant.name == name)
+            .cloned()
+    }
+
+    /// Every room with an occupant of this name, sorted. More than one means the
+    /// single room invariant has been broken.
+    fn rooms_of(&self, name: &str) -> Vec<Room> {
+        let mut rooms: Vec<Room> = self
+            .occupancy
+            .iter()
+            .filter(|(_, occupants)| occupants.iter().any(|occupant| occupant.name == name))
+            .map(|(room, _)| room.clone())
+            .collect();
+        rooms.sort_by(|a, b| a.name.cmp(&b.name));
+        rooms
+    }
+
+    /// The user who most recently left under this name, if they can still resume
+    /// and so come back for their pages.
+    fn find_departed_by_name(&self, name: &str) -> Option<User> {
+        let cutoff = Utc::now()
+            .checked_sub_signed(self.resume_ttl)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        self.departed
+            .iter()
+            .filter(|(user, (_, left_at))| user.name == name && *left_at > cutoff)
+            .max_by_key(|(_, (_, left_at))| *left_at)
+            .map(|(user, _)| user.clone())
+    }
+
+    fn ignores(&self, user: &User, sender: &User) -> bool {
+        self.ignored
+            .get(&user.id)
+            .is_some_and(|ignored| ignored.contains(&sender.id))
+    }
+
+    /// Holds a page for a user who has left but can still resume, keeping only the
+    /// most recent max_pages for any one user. Returns false without storing once
+    /// max_stored_pages are held in total.
+    fn store_page(&mut self, target: &User, page: NotificationLog) -> bool {
+        if self.pages.values().map(VecDeque::len).sum::<usize>() >= self.max_stored_pages {
+            return false;
+        }
+        let pages = self.pages.entry(target.id.clone()).or_default();
+        pages.push_back(page);
+        if pages.len() > self.max_pages {
+            pages.pop_front();
+        }
+        true
+    }
+
+    /// Removes and returns the pages held for a user, dropping any older than page_ttl.
+    fn take_pages(&mut self, user: &User) -> Vec<NotificationLog> {
+        let cutoff = Utc::now()
+            .checked_sub_signed(self.page_ttl)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        self.pages
+            .remove(&user.id)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|page| page.timestamp > cutoff)
+            .collect()
+    }
+
+    /// Drops pages older than page_ttl, and the pages and ignore lists of users who
+    /// are neither online nor able to resume, since no one can collect them.
+    fn expire_pages(&mut self, now: DateTime<Utc>) {
+        let page_cutoff = now
+            .checked_sub_signed(self.page_ttl)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let resume_cutoff = now
+            .checked_sub_signed(self.resume_ttl)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let known: HashSet<&String> = self
+            .departed
+            .iter()
+            .filter(|(_, (_, left_at))| *left_at > resume_cutoff)
+            .map(|(user, _)| &user.id)
+            .chain(self.occupancy.values().flatten().map(|user| &user.id))
+            .collect();
+        self.pages.retain(|id, pages| {
+            pages.retain(|page| page.timestamp > page_cutoff);
+            !pages.is_empty() && known.contains(id)
+        });
+        self.ignored.retain(|id, _| known.contains(id));
+    }
+
+    /// Drops chat messages older than their room's message ttl, returning the ids
+    /// removed from each room.
+    fn expire_messages(&mut self, now: DateTime<Utc>) -> Vec<(Room, Vec<u64>)> {
+        let mut expired = vec![];
+        for (room, ttl) in &self.message_ttls {
+            let Some(logs) = self.chat_logs.get_mut(room) else {
+                continue;
+            };
+            let cutoff = now
+                .checked_sub_signed(*ttl)
+                .unwrap_or(DateTime::<Utc>::MIN_UTC);
+            let mut ids = vec![];
+            logs.retain(|msg| {
+                let keep = msg.timestamp > cutoff;
+                if !keep {
+                    ids.push(msg.id);
+                }
+                keep
+            });
+            if !ids.is_empty() {
+                expired.push((room.clone(), ids));
+            }
+        }
+        expired
+    }
+
+    /// Counts a chat message towards the rolling messages per minute rate.
+    fn count_message(&mut self) {
+        self.recent_messages.push_back(Utc::now());
+    }
+
+    /// Allows at most MARAIN_RATE_LIMIT chat messages per user in any rolling
+    /// rate_limit_window, with no limit while it is unset. Allowed messages count
+    /// towards the limit, refused ones do not.
+    fn allow_message(&mut self, user: &User) -> bool {
+        let Some(limit) = self.rate_limit else {
+            return true;
+        };
+        let now = Utc::now();
+        let cutoff = now - self.rate_limit_window;
+        let sent = self.sent_messages.entry(user.id.clone()).or_default();
+        while sent.front().is_some_and(|at| *at <= cutoff) {
+            sent.pop_front();
+        }
+        if sent.len() >= limit {
+            return false;
+        }
+        sent.push_back(now);
+        true
+    }
+
+    fn messages_per_min(&mut self) -> usize {
+        let cutoff = Utc::now() - Duration::minutes(1);
+        while let Some(sent) = self.recent_messages.front() {
+            if *sent > cutoff {
+                break;
+            }
+            self.recent_messages.pop_front();
+        }
+        self.recent_messages.len()
+    }
+
+    /// Registers an empty room in each of the parallel per room maps.
+    fn create_room(&mut self, room: &Room) {
+        self.occupancy.entry(room.clone()).or_default();
+        self.chat_logs.entry(room.clone()).or_default();
+        self.notifications.entry(room.clone()).or_default();
+    }
+
+    /// Puts the user in the room, taking them out of any other room first so a
+    /// user can never occupy two rooms regardless of what the caller did.
+    fn add_user_to_room(&mut self, user: &User, room: &Room) {
+        for (occupied, occupants) in self.occupancy.iter_mut() {
+            if occupied != room && occupants.contains(user) {
+                log::warn!("{user:?} was still in {occupied:?} when joining {room:?}, removing");
+                occupants.retain(|occupant| occupant != user);
+            }
+        }
+
+        // Getting into any room ends a wait for another.
+        self.leave_waitlists(user);
+        // Keep the parallel per room maps in lockstep so reads never miss a room.
+        self.create_room(room);
+        let occupants = self.occupancy.entry(room.clone()).or_default();
+        if !occupants.contains(user) {
+            occupants.push(user.clone());
+            self.joined_at.insert(user.clone(), Utc::now());
+        }
+    }
+
+    /// Swaps the user for a copy under the new name everywhere state is keyed on
+    /// the User, and returns the renamed copy.
+    fn leave_waitlists(&mut self, user: &User) {
+        for queue in self.waitlists.values_mut() {
+            queue.retain(|waiting| waiting != user);
+        }
+        self.waitlists.retain(|_, queue| !queue.is_empty());
+    }
+
+    fn rename_user(&mut self, user: &User, new_name: &str) -> User {
+        let mut renamed = user.clone();
+        renamed.name = new_name.to_string();
+        for occupant in self.occupancy.values_mut().flatten() {
+            if occupant == user {
+                *occupant = renamed.clone();
+            }
+        }
+        if let Some(joined_at) = self.joined_at.remove(user) {
+            self.joined_at.insert(renamed.clone(), joined_at);
+        }
+        if let Some(previous) = self.previous_rooms.remove(user) {
+            self.previous_rooms.insert(renamed.clone(), previous);
+        }
+        for waiting in self.waitlists.values_mut().flatten() {
+            if waiting == user {
+                *waiting = renamed.clone();
+            }
+        }
+        renamed
+    }
+
+    /// Forgets a room along with everything kept for it in the per room maps.
+    fn remove_room(&mut self, room: &Room) {
+        self.occupancy.remove(room);
+        self.chat_logs.remove(room);
+        self.notifications.remove(room);
+        self.room_creators.remove(room);
+        self.message_ttls.remove(room);
+        self.capacities.remove(room);
+        self.waitlists.remove(room);
+        self.history_limits.remove(room);
+        self.read_only_rooms.remove(room);
+        self.created_rooms.remove(room);
+        self.previous_rooms.retain(|_, previous| previous != room);
+    }
+
+    /// Drops a room made by moving into it once nobody is left in it. Operators
+    /// who want to keep the history of empty rooms leave collection off.
+    fn collect_if_empty(&mut self, room: &Room) {
+        let collecting = self.gc_empty_rooms || self.max_rooms.is_some();
+        if collecting
+            && *room != Room::default()
+            && !self.created_rooms.contains(room)
+            && self.room_subscribers(room).is_empty()
+        {
+            self.remove_room(room);
+        }
     }
 
     fn get_occupied_room(&self, user: &User) -> Option<Room> {
@@ -145,42 +680,105 @@ impl AppState {
             return;
         };
 
-        occupants.swap_remove(index);
+        // remove rather than swap_remove, so the others keep their join order.
+        occupants.remove(index);
+        self.joined_at.remove(user);
         self.record_notification(user, notice);
     }
 
-    fn record_chat_message(&mut self, user: &User, msg: MessageLog) -> &[User] {
+    /// Where a registering user starts out. A resumed login taking over a live
+    /// session stays put, one resumed within the resume window goes back to the
+    /// room it left, provided the room still exists, and anyone else starts in the Hub.
+    fn resume_room(&mut self, user: &User) -> Room {
+        if let Some(room) = self.get_occupied_room(user) {
+            return room;
+        }
+        let cutoff = Utc::now() - self.resume_ttl;
+        match self.departed.remove(user) {
+            Some((room, left_at)) if left_at > cutoff && self.occupancy.contains_key(&room) => room,
+            _ => Room::default(),
+        }
+    }
+
+    /// Users held past the occupancy grace period, who are no longer held once
+    /// returned.
+    fn expire_disconnected(&mut self, now: DateTime<Utc>) -> Vec<User> {
+        let cutoff = now
+            .checked_sub_signed(self.occupancy_grace)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let expired: Vec<User> = self
+            .disconnected
+            .iter()
+            .filter(|(_, dropped_at)| **dropped_at <= cutoff)
+            .map(|(user, _)| user.clone())
+            .collect();
+        for user in &expired {
+            self.disconnected.remove(user);
+        }
+        expired
+    }
+
+    fn record_departure(&mut self, user: &User, room: Room) {
+        let cutoff = Utc::now() - self.resume_ttl;
+        self.departed.retain(|_, (_, left_at)| *left_at > cutoff);
+        self.departed.insert(user.clone(), (room, Utc::now()));
+    }
+
+    /// The occupants of the room the message was recorded in, or None when the user
+    /// is in no room, for instance a message that raced ahead of their join.
+    fn record_chat_message(&mut self, user: &User, msg: MessageLog) -> Option<&[User]> {
         for (room, occupants) in &self.occupancy {
             if occupants.contains(user) {
                 // log::info!("{}", room.name);
-                self.chat_logs
-                    .entry(room.clone())
-                    .and_modify(|logs| {
-                        logs.push_back(msg.clone());
-                        if logs.len() > self.max_logs {
-                            logs.pop_front();
+                let limit = *self.history_limits.get(room).unwrap_or(&self.max_logs);
+                let logs = self.chat_logs.entry(room.clone()).or_default();
+                logs.push_back(msg);
+                while logs.len() > limit {
+                    if let Some(evicted) = logs.pop_front() {
+                        if self.announce_evictions {
+                            self.evicted.push((room.clone(), evicted.id));
                         }
-                    })
-                    .or_insert(vec![msg].into());
-                return occupants;
+                    }
+                }
+                return Some(occupants);
             }
         }
 
-        return &[];
+        None
+    }
+
+    fn find_chat_message(&self, room: &Room, id: u64) -> Option<MessageLog> {
+        self.chat_logs
+            .get(room)?
+            .iter()
+            .find(|msg| msg.id == id)
+            .cloned()
+    }
+
+    /// Records a message straight into a room's log, for messages whose sender is
+    /// not necessarily an occupant of that room.
+    fn record_room_message(&mut self, room: &Room, msg: MessageLog) {
+        let limit = self.history_limit(room);
+        let logs = self.chat_logs.entry(room.clone()).or_default();
+        logs.push_back(msg);
+        while logs.len() > limit {
+            if let Some(evicted) = logs.pop_front() {
+                if self.announce_evictions {
+                    self.evicted.push((room.clone(), evicted.id));
+                }
+            }
+        }
     }
 
     fn record_notification(&mut self, user: &User, notice: NotificationLog) {
         for (room, occupants) in &self.occupancy {
             if occupants.contains(user) {
-                self.notifications
-                    .entry(room.clone())
-                    .and_modify(|logs| {
-                        logs.push_back(notice.clone());
-                        if logs.len() > self.max_logs {
-                            logs.pop_front();
-                        }
-                    })
-                    .or_insert(vec![notice.clone()].into());
+                let limit = *self.history_limits.get(room).unwrap_or(&self.max_logs);
+                let logs = self.notifications.entry(room.clone()).or_default();
+                logs.push_back(notice.clone());
+                while logs.len() > limit {
+                    logs.pop_front();
+                }
             }
         }
     }
@@ -195,7 +793,37 @@ impl CommandHandler {
         Self { state }
     }
 
-    fn handle(&mut self, command: Command, event_buf: &mut VecDeque<Broadcast>) -> Result<()> {
+    /// The error for a command the permissions file or the admin gate does not let
+    /// its sender use, or None if it may go ahead.
+    fn refuse_command(&self, command: &Command) -> Option<Broadcast> {
+        let user = &command.user;
+        // Joining and leaving are part of the session lifecycle and cannot be disabled.
+        let lifecycle = matches!(
+            command.payload,
+            CommandPayload::RegisterUser(..) | CommandPayload::DropUser(..)
+        );
+        let kind = command.payload.kind();
+        let message = if !lifecycle && !self.state.permissions.allows(kind, user.role()) {
+            format!("{kind} is not available to you on this server")
+        } else if command.payload.requires_admin() && !user.is_admin {
+            format!("Only admins can use {kind}")
+        } else {
+            return None;
+        };
+        Some(Broadcast::new(
+            Event::Error {
+                code: ErrorCode::Forbidden,
+                message,
+            },
+            vec![user.clone()],
+        ))
+    }
+
+    fn handle(
+        &mut self,
+        command: Command,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) -> Result<(), MarainServerError> {
         // Changed return from Result<Broadcast> -> Result<Vec<Broadcast>> -> Result<()>.
         // This is because some Commands may produce multiple broadcasts,
         // eg. MoveRoom should produce a UserLeft & UserJoined Broadcast for each event.
@@ -205,101 +833,1279 @@ impl CommandHandler {
 
         let user = command.user.clone();
 
+        if let Some(refusal) = self.refuse_command(&command) {
+            event_buf.push_back(refusal);
+            return Ok(());
+        }
+
         match command.payload.clone() {
-            CommandPayload::DropUser => {
+            CommandPayload::DropUser(..) => {
                 self.handle_drop_user(&user, event_buf);
                 Ok(())
             }
 
             CommandPayload::RegisterUser(..) => {
                 event_buf.push_back(self.register_user(user.clone()));
-                event_buf.push_back(self.insert_occupant(&user, &Room::from("Hub")));
+                let held = self.state.disconnected.remove(&user).is_some();
+                let room = self.state.resume_room(&user);
+                if held {
+                    // The room never saw them go, so only they are sent the room.
+                    event_buf.push_back(Broadcast::new(
+                        Event::UserJoined {
+                            user: user.clone(),
+                            room: room.clone(),
+                            occupant_names: self.state.occupant_names(&room),
+                            notifications: self.state.room_notifications(&room),
+                            msg_log: self.state.room_chat_logs(&room),
+                        },
+                        vec![user.clone()],
+                    ));
+                } else {
+                    event_buf.extend(self.insert_occupant(&user, &room));
+                }
+                let pages = self.state.take_pages(&user);
+                if !pages.is_empty() {
+                    event_buf.push_back(Broadcast::new(
+                        Event::Notify { notice: pages },
+                        vec![user.clone()],
+                    ));
+                }
                 Ok(())
             }
 
-            CommandPayload::MoveUser { target_room } => {
-                match self.remove_occupant(&user) {
-                    Some(broadcast) => {
-                        event_buf.push_back(broadcast);
-                    }
-                    None => {
-                        log::error!("Failed to remove occupant: {user:?} in response to command.")
-                    }
-                }
-                event_buf.push_back(self.insert_occupant(&user, &target_room));
+            CommandPayload::MoveUser {
+                target_room,
+                create_if_missing,
+            } => {
+                self.handle_move_user(&user, target_room, create_if_missing, event_buf);
+                Ok(())
+            }
+            CommandPayload::SwapRoom => {
+                let Some(previous) = self.state.previous_rooms.get(&user).cloned() else {
+                    event_buf.push_back(Broadcast::new(
+                        Event::Notify {
+                            notice: vec![NotificationLog::new(
+                                "There is no room to swap back to".into(),
+                            )
+                            .with_severity(Severity::Warning)],
+                        },
+                        vec![user.clone()],
+                    ));
+                    return Ok(());
+                };
+                let target_room = if self.state.occupancy.contains_key(&previous) {
+                    previous
+                } else {
+                    Room::default()
+                };
+                self.handle_move_user(&user, target_room, false, event_buf);
                 Ok(())
             }
-            CommandPayload::RecordMessage { message } => {
-                let msg_log = MessageLog::from_user(&user, message);
-                let recipients: Vec<User> =
-                    Vec::from(self.state.record_chat_message(&user, msg_log.clone()));
+            CommandPayload::RecordMessage { message, reply_to } => {
+                let room = self.state.get_occupied_room(&user);
+                if let Some(refusal) = self.refuse_message(&user, room.as_ref(), &message) {
+                    event_buf.push_back(Broadcast::new(refusal, vec![user.clone()]));
+                    return Ok(());
+                }
+                let mut msg_log = MessageLog::from_user(&user, message);
+                if let Some(id) = reply_to {
+                    let available = self
+                        .state
+                        .get_occupied_room(&user)
+                        .and_then(|room| self.state.find_chat_message(&room, id))
+                        .is_some();
+                    msg_log = msg_log.with_reply_to(ReplyTo { id, available });
+                }
+                let Some(recipients) = self.state.record_chat_message(&user, msg_log.clone())
+                else {
+                    event_buf.push_back(Broadcast::new(
+                        Event::Error {
+                            code: ErrorCode::NotFound,
+                            message: "Message not sent, you are not in a room yet".into(),
+                        },
+                        vec![user.clone()],
+                    ));
+                    return Ok(());
+                };
+                let recipients = recipients.to_vec();
+                self.state.count_message();
 
-                let br = Broadcast::new(Event::MsgReceived { msg: msg_log }, recipients);
+                let mentioned: Vec<User> = command_parser::mentions(&msg_log.contents)
+                    .iter()
+                    .filter_map(|name| recipients.iter().find(|occupant| occupant.name == *name))
+                    .filter(|occupant| **occupant != user)
+                    .cloned()
+                    .collect();
+                let br = Broadcast::new(
+                    Event::MsgReceived {
+                        msg: msg_log.clone(),
+                    },
+                    recipients,
+                );
                 event_buf.push_back(br);
+                // Unknown names and people outside the room are ignored.
+                if !mentioned.is_empty() {
+                    event_buf.push_back(Broadcast::new(
+                        Event::Mentioned {
+                            msg: msg_log,
+                            mentioned_users: mentioned.iter().map(|m| m.name.clone()).collect(),
+                        },
+                        mentioned,
+                    ));
+                }
+                self.announce_evictions(event_buf);
+                Ok(())
+            }
+            CommandPayload::Page {
+                target_name,
+                message,
+            } => {
+                self.handle_page(&user, target_name, message, event_buf);
+                Ok(())
+            }
+            CommandPayload::Ignore {
+                target_name,
+                ignore,
+            } => {
+                self.handle_ignore(&user, target_name, ignore, event_buf);
+                Ok(())
+            }
+            CommandPayload::JoinOrWait { room } => {
+                self.handle_join_or_wait(&user, room, event_buf);
+                Ok(())
+            }
+            CommandPayload::DirectMessage {
+                target_name,
+                message,
+            } => {
+                let broadcast = match self.state.find_user_by_name(&target_name) {
+                    Some(target) => match self.refuse_message(&user, None, &message) {
+                        Some(refusal) => Broadcast::new(refusal, vec![user.clone()]),
+                        None => {
+                            // A message to yourself arrives once, not as sender and recipient.
+                            let mut recipients = vec![user.clone()];
+                            if target != user {
+                                recipients.push(target);
+                            }
+                            Broadcast::new(
+                                Event::DirectMsgReceived {
+                                    msg: MessageLog::from_user(&user, message),
+                                },
+                                recipients,
+                            )
+                        }
+                    },
+                    None => Broadcast::new(
+                        Event::Notify {
+                            notice: vec![NotificationLog::new(format!(
+                                "Could not deliver your message, {target_name} is not online"
+                            ))
+                            .with_severity(Severity::Warning)],
+                        },
+                        vec![user.clone()],
+                    ),
+                };
+                event_buf.push_back(broadcast);
+                Ok(())
+            }
+            CommandPayload::FetchNotifications { limit } => {
+                let notifications = self.latest_notifications(&user, limit);
+                event_buf.push_back(Broadcast::new(
+                    Event::NotificationPage { notifications },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::GlobalNotifications { limit } => {
+                event_buf.push_back(Broadcast::new(
+                    Event::GlobalNotifications {
+                        notifications: self.state.latest_global_notifications(limit),
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::RoomInfo { room } => {
+                // Only reads occupancy, unlike MoveUser this never creates the room.
+                let info = Event::RoomInfo {
+                    exists: self.state.occupancy.contains_key(&room),
+                    occupant_count: self.state.room_subscribers(&room).len(),
+                    room,
+                };
+                event_buf.push_back(Broadcast::new(info, vec![user.clone()]));
+                Ok(())
+            }
+            CommandPayload::ListRooms => {
+                event_buf.push_back(Broadcast::new(
+                    Event::RoomList {
+                        rooms: self.state.list_rooms(),
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::SearchRooms { prefix, limit } => {
+                let rooms = self.state.search_rooms(&prefix, limit);
+                event_buf.push_back(Broadcast::new(
+                    Event::RoomSearchResults { rooms },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::RoomRoster { page } => {
+                let room = self.state.get_occupied_room(&user).unwrap_or_default();
+                let roster = self.state.roster(&room);
+                let pages = roster.len().div_ceil(ROSTER_PAGE_SIZE).max(1);
+                let page = page.clamp(1, pages);
+                let members = roster
+                    .into_iter()
+                    .skip((page - 1) * ROSTER_PAGE_SIZE)
+                    .take(ROSTER_PAGE_SIZE)
+                    .collect();
+                event_buf.push_back(Broadcast::new(
+                    Event::Roster {
+                        room,
+                        members,
+                        page,
+                        pages,
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::CrossPost {
+                message_id,
+                target_room,
+            } => {
+                self.handle_cross_post(&user, message_id, target_room, event_buf);
+                Ok(())
+            }
+            CommandPayload::GetRoomData => {
+                // Occupancy is left alone, this only repeats what joining would send.
+                let room = self.state.get_occupied_room(&user).unwrap_or_default();
+                event_buf.push_back(Broadcast::new(
+                    Event::RoomData {
+                        msg_log: self.state.room_chat_logs(&room),
+                        notifications: self.state.room_notifications(&room),
+                        occupant_names: self.state.occupant_names(&room),
+                        room,
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::GetRecipients => {
+                let room = self.state.get_occupied_room(&user);
+                let occupant_names = match &room {
+                    Some(room) => self.state.occupant_names(room),
+                    None => vec![],
+                };
+                event_buf.push_back(Broadcast::new(
+                    Event::Recipients {
+                        room,
+                        occupant_names,
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::GetLimits => {
+                let limits = Event::Limits {
+                    max_rooms: self.state.max_rooms_per_user,
+                    max_message_bytes: self.state.max_message_bytes,
+                    history_window: self
+                        .state
+                        .history_limit(&self.state.get_occupied_room(&user).unwrap_or_default()),
+                    features: CLIENT_COMMAND_KINDS
+                        .iter()
+                        .filter(|kind| self.state.permissions.allows(kind, user.role()))
+                        .map(|kind| kind.to_string())
+                        .collect(),
+                };
+                event_buf.push_back(Broadcast::new(limits, vec![user.clone()]));
+                Ok(())
+            }
+            CommandPayload::UserRooms { target_name } => {
+                let rooms = self.state.rooms_of(&target_name);
+                if rooms.len() > 1 {
+                    log::warn!("{target_name} occupies {} rooms: {rooms:?}", rooms.len());
+                }
+                let event = Event::UserRooms {
+                    name: target_name,
+                    rooms: rooms.into_iter().map(|room| room.name).collect(),
+                };
+                event_buf.push_back(Broadcast::new(event, vec![user.clone()]));
+                Ok(())
+            }
+            CommandPayload::SetHistoryLimit { limit } => {
+                let room = self.state.get_occupied_room(&user).unwrap_or_default();
+                self.state.history_limits.insert(room.clone(), limit);
+                let notice = NotificationLog::new(format!(
+                    "{} now keeps the last {limit} messages",
+                    room.name
+                ));
+                event_buf.push_back(Broadcast::new(
+                    Event::Notify {
+                        notice: vec![notice],
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::SetCapacity { capacity } => {
+                self.handle_set_capacity(&user, capacity, event_buf);
+                Ok(())
+            }
+            CommandPayload::Rename { new_name } => {
+                self.handle_rename(&user, new_name, event_buf);
+                Ok(())
+            }
+            CommandPayload::EditMessage {
+                message_id,
+                new_contents,
+            } => {
+                self.handle_edit_message(&user, message_id, new_contents, event_buf);
+                Ok(())
+            }
+            CommandPayload::DeleteMessage { message_id } => {
+                self.handle_delete_message(&user, message_id, event_buf);
+                Ok(())
+            }
+            CommandPayload::CreateRoom {
+                room,
+                capacity,
+                read_only,
+            } => {
+                self.handle_create_room(&user, room, capacity, read_only, event_buf);
+                Ok(())
+            }
+            CommandPayload::CloseRoom { room } => {
+                self.handle_close_room(&user, room, event_buf);
+                Ok(())
+            }
+            CommandPayload::Typing { is_typing } => {
+                // At most one update per typing_interval, later ones saying the same
+                // are dropped. A change always goes through, or a stop sent just after
+                // starting would leave everyone else seeing them typing.
+                let now = Utc::now();
+                if let Some((last, was_typing)) = self.state.last_typing.get(&user.id) {
+                    if *was_typing == is_typing && now - *last < self.state.typing_interval {
+                        return Ok(());
+                    }
+                }
+                let Some(room) = self.state.get_occupied_room(&user) else {
+                    return Ok(());
+                };
+                self.state
+                    .last_typing
+                    .insert(user.id.clone(), (now, is_typing));
+                let others: Vec<User> = self
+                    .state
+                    .room_subscribers(&room)
+                    .into_iter()
+                    .filter(|occupant| *occupant != user)
+                    .collect();
+                event_buf.push_back(Broadcast::new(
+                    Event::TypingChanged {
+                        user: user.clone(),
+                        room,
+                        is_typing,
+                    },
+                    others,
+                ));
+                Ok(())
+            }
+            CommandPayload::SetReadOnly { read_only } => {
+                let room = self.state.get_occupied_room(&user).unwrap_or_default();
+                let notice = if read_only {
+                    self.state.read_only_rooms.insert(room.clone());
+                    format!("{} is now read only", room.name)
+                } else {
+                    self.state.read_only_rooms.remove(&room);
+                    format!("Everyone can post in {} again", room.name)
+                };
+                let notice = NotificationLog::from_user(&user, notice);
+                self.state.record_notification(&user, notice.clone());
+                event_buf.push_back(Broadcast::new(
+                    Event::Notify {
+                        notice: vec![notice],
+                    },
+                    self.state.room_subscribers(&room),
+                ));
+                Ok(())
+            }
+            CommandPayload::KickUser {
+                target_name,
+                reason,
+            } => {
+                let Some(target) = self.state.find_user_by_name(&target_name) else {
+                    event_buf.push_back(Broadcast::new(
+                        Event::Error {
+                            code: ErrorCode::NotFound,
+                            message: format!("{target_name} is not online"),
+                        },
+                        vec![user.clone()],
+                    ));
+                    return Ok(());
+                };
+                log::info!("{} kicked {target_name}: {reason}", user.name);
+                // The kicked session ends on Kicked and its DropUser unsubscribes it.
+                event_buf.push_back(Broadcast::new(
+                    Event::Kicked { reason },
+                    vec![target.clone()],
+                ));
+                self.handle_drop_user(&target, event_buf);
+                event_buf.push_back(Broadcast::new(
+                    Event::Notify {
+                        notice: vec![NotificationLog::new(format!("Kicked {target_name}"))],
+                    },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::SetMessageTtl { seconds } => {
+                self.handle_set_message_ttl(&user, seconds, event_buf);
+                Ok(())
+            }
+            CommandPayload::GetMessage { id } => {
+                // Only messages in the requester's own room are visible to them.
+                let message = self
+                    .state
+                    .get_occupied_room(&user)
+                    .and_then(|room| self.state.find_chat_message(&room, id));
+                event_buf.push_back(Broadcast::new(
+                    Event::SingleMessage { message },
+                    vec![user.clone()],
+                ));
+                Ok(())
+            }
+            CommandPayload::MoveAll { from, to } => {
+                self.handle_move_all(&user, from, to, event_buf);
                 Ok(())
             }
-            _ => Err(anyhow!("{:?} not implemented in CommandHandler", command)),
+            _ => Err(MarainServerError::Unsupported(format!(
+                "{:?} not implemented in CommandHandler",
+                command
+            ))),
         }
     }
 
-    fn handle_drop_user(&mut self, user: &User, event_buf: &mut VecDeque<Broadcast>) {
-        let room = self
-            .state
-            .get_occupied_room(&user)
-            .unwrap_or(Room::default());
-        let mut subscribers = self.state.room_subscribers(&room);
-        if !subscribers.contains(&user) {
-            subscribers.push(user.clone());
-        }
+    /// The most recent notifications for the user's room, at most its history limit.
+    fn latest_notifications(&self, user: &User, limit: usize) -> Vec<NotificationLog> {
+        let Some(room) = self.state.get_occupied_room(user) else {
+            return vec![];
+        };
+        let notifications = self.state.room_notifications(&room);
+        let skip = notifications
+            .len()
+            .saturating_sub(limit.min(self.state.history_limit(&room)));
+        notifications.into_iter().skip(skip).collect()
+    }
 
-        let broadcast = self.remove_occupant(&user).unwrap_or(Broadcast {
-            event: Event::UserLeft {
-                user: user.clone(),
-                room: room.clone(),
-                msg_log: vec![],
-                notifications: vec![],
-                occupant_names: self.state.occupant_names(&room),
-            },
-            subscribers,
-        });
-        event_buf.push_back(broadcast);
+    /// The error for a chat message that is too long, meant for a read only room the
+    /// sender may not post in, or over the sender's rate limit, or None if it may be
+    /// sent. Chat entering a room by any route goes through here, and so do direct
+    /// messages, which have no room. Passing counts towards the rate limit, so call
+    /// it last.
+    fn refuse_message(
+        &mut self,
+        user: &User,
+        room: Option<&Room>,
+        contents: &str,
+    ) -> Option<Event> {
+        if contents.len() > self.state.max_message_bytes {
+            return Some(Event::Error {
+                code: ErrorCode::MessageTooLong,
+                message: format!(
+                    "Message not sent, it is {} bytes and the limit is {}",
+                    contents.len(),
+                    self.state.max_message_bytes
+                ),
+            });
+        }
+        let read_only = room.is_some_and(|room| self.state.read_only_rooms.contains(room));
+        if read_only && !user.is_admin {
+            return Some(Event::Error {
+                code: ErrorCode::Forbidden,
+                message: "Message not sent, this room is read only".into(),
+            });
+        }
+        if !self.state.allow_message(user) {
+            return Some(Event::Error {
+                code: ErrorCode::RateLimited,
+                message: "Message not sent, you are sending messages too quickly".into(),
+            });
+        }
+        None
     }
 
-    fn register_user(&mut self, user: User) -> Broadcast {
-        Broadcast::new(
-            Event::UserRegistered {
-                token: user.id.clone(),
+    fn handle_cross_post(
+        &mut self,
+        user: &User,
+        message_id: u64,
+        target_room: Room,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let original = self
+            .state
+            .get_occupied_room(user)
+            .and_then(|room| Some((self.state.find_chat_message(&room, message_id)?, room)));
+        let notice = match original {
+            None => NotificationLog::new(format!(
+                "Message {message_id} is no longer available to cross-post"
+            ))
+            .with_severity(Severity::Warning),
+            Some(_) if !self.state.occupancy.contains_key(&target_room) => {
+                NotificationLog::new(format!("{} does not exist", target_room.name))
+                    .with_severity(Severity::Warning)
+            }
+            Some(_) if self.state.room_is_full(&target_room) => {
+                NotificationLog::new(format!("{} is full", target_room.name))
+                    .with_severity(Severity::Warning)
+            }
+            Some((original, source_room)) => {
+                let cross_post = MessageLog::from_user(
+                    user,
+                    format!(
+                        "{} cross-posted from {}: [{}] {}",
+                        user.name, source_room.name, original.username, original.contents
+                    ),
+                );
+                if let Some(refusal) =
+                    self.refuse_message(user, Some(&target_room), &cross_post.contents)
+                {
+                    event_buf.push_back(Broadcast::new(refusal, vec![user.clone()]));
+                    return;
+                }
+                self.state
+                    .record_room_message(&target_room, cross_post.clone());
+                self.state.count_message();
+                event_buf.push_back(Broadcast::new(
+                    Event::MsgReceived { msg: cross_post },
+                    self.state.room_subscribers(&target_room),
+                ));
+                self.announce_evictions(event_buf);
+                NotificationLog::new(format!(
+                    "Cross-posted message {message_id} to {}",
+                    target_room.name
+                ))
+            }
+        };
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
             },
             vec![user.clone()],
-        )
+        ));
     }
 
-    fn remove_occupant(&mut self, user: &User) -> Option<Broadcast> {
-        let Some(current_room) = self.state.get_occupied_room(user) else {
-            return None;
+    fn handle_move_user(
+        &mut self,
+        user: &User,
+        target_room: Room,
+        create_if_missing: bool,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        if !self.state.occupancy.contains_key(&target_room) {
+            if !create_if_missing {
+                event_buf.push_back(Broadcast::new(
+                    Event::JoinRejected {
+                        room: target_room,
+                        reason: "The room does not exist".into(),
+                    },
+                    vec![user.clone()],
+                ));
+                return;
+            }
+            if let Err(reason) = self.state.can_create_room(user) {
+                event_buf.push_back(Broadcast::new(
+                    Event::JoinRejected {
+                        room: target_room,
+                        reason,
+                    },
+                    vec![user.clone()],
+                ));
+                return;
+            }
+            self.state
+                .room_creators
+                .insert(target_room.clone(), user.id.clone());
+        }
+
+        if self.state.room_is_full(&target_room) {
+            event_buf.push_back(Broadcast::new(
+                Event::JoinRejected {
+                    room: target_room,
+                    reason: "The room is full".into(),
+                },
+                vec![user.clone()],
+            ));
+            return;
+        }
+
+        let current = self.state.get_occupied_room(user);
+        // Leaving first would collect the room if the user is its only occupant.
+        if current.as_ref() != Some(&target_room) {
+            if let Some(current) = current {
+                self.state.previous_rooms.insert(user.clone(), current);
+            }
+            let left = self.remove_occupant(user);
+            if left.is_empty() {
+                log::error!("Failed to remove occupant: {user:?} in response to command.")
+            }
+            event_buf.extend(left);
+        }
+        event_buf.extend(self.insert_occupant(user, &target_room));
+    }
+
+    /// Joins the room as /join would, unless it is full, in which case the user is
+    /// queued for it and told their place. Asking again keeps that place, asking
+    /// for another room gives it up.
+    fn handle_join_or_wait(
+        &mut self,
+        user: &User,
+        room: Room,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let full = self.state.occupancy.contains_key(&room)
+            && self.state.room_is_full(&room)
+            && self.state.get_occupied_room(user).as_ref() != Some(&room);
+        if !full {
+            self.handle_move_user(user, room, false, event_buf);
+            return;
+        }
+        let waiting = self
+            .state
+            .waitlists
+            .get(&room)
+            .and_then(|queue| queue.iter().position(|waiting| waiting == user));
+        let position = match waiting {
+            Some(index) => index + 1,
+            None => {
+                self.state.leave_waitlists(user);
+                let queue = self.state.waitlists.entry(room.clone()).or_default();
+                queue.push_back(user.clone());
+                queue.len()
+            }
         };
-        let notice = NotificationLog::new(format!("{} left {}", user.name, current_room.name));
+        event_buf.push_back(Broadcast::new(
+            Event::Waitlisted { room, position },
+            vec![user.clone()],
+        ));
+    }
 
-        self.state.remove_user_from_room(user, notice);
-        Some(Broadcast::new(
-            Event::UserLeft {
-                user: user.clone(),
-                room: current_room.clone(),
-                occupant_names: self.state.occupant_names(&current_room),
-                notifications: self.state.room_notifications(&current_room),
-                msg_log: self.state.room_chat_logs(&current_room),
+    /// Moves users queued for the room into it, first come first served, for as
+    /// long as it has space.
+    fn admit_waiting(&mut self, room: &Room) -> Vec<Broadcast> {
+        let mut event_buf = VecDeque::new();
+        while !self.state.room_is_full(room) {
+            let Some(next) = self
+                .state
+                .waitlists
+                .get_mut(room)
+                .and_then(VecDeque::pop_front)
+            else {
+                break;
+            };
+            self.handle_move_user(&next, room.clone(), false, &mut event_buf);
+        }
+        event_buf.into()
+    }
+
+    fn handle_set_message_ttl(
+        &mut self,
+        user: &User,
+        seconds: Option<u64>,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let notice = match seconds {
+            Some(seconds) => {
+                let ttl = i64::try_from(seconds)
+                    .ok()
+                    .and_then(Duration::try_seconds)
+                    .filter(|ttl| *ttl <= self.state.max_message_ttl);
+                let Some(ttl) = ttl else {
+                    event_buf.push_back(Broadcast::new(
+                        Event::Error {
+                            code: ErrorCode::InvalidArgument,
+                            message: format!(
+                                "Messages cannot be kept for more than {}s",
+                                self.state.max_message_ttl.num_seconds()
+                            ),
+                        },
+                        vec![user.clone()],
+                    ));
+                    return;
+                };
+                self.state.message_ttls.insert(room.clone(), ttl);
+                format!("Messages in {} now expire after {seconds}s", room.name)
+            }
+            None => {
+                self.state.message_ttls.remove(&room);
+                format!("Messages in {} no longer expire", room.name)
+            }
+        };
+        let notice = NotificationLog::from_user(user, notice);
+        self.state.record_notification(user, notice.clone());
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
             },
-            self.state.room_subscribers(&current_room),
-        ))
+            self.state.room_subscribers(&room),
+        ));
     }
 
-    fn insert_occupant(&mut self, user: &User, room: &Room) -> Broadcast {
-        self.state.add_user_to_room(user, &room);
-        self.state.record_notification(
-            user,
-            NotificationLog::new(format!("{} joined {}", user.name, room.name)),
-        );
+    fn handle_set_capacity(
+        &mut self,
+        user: &User,
+        capacity: Option<usize>,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let notice = if room == Room::default() {
+            NotificationLog::new(format!("{} cannot have a capacity", room.name))
+                .with_severity(Severity::Error)
+        } else {
+            match capacity {
+                Some(capacity) => {
+                    self.state.capacities.insert(room.clone(), capacity);
+                    NotificationLog::new(format!(
+                        "{} now holds at most {capacity} occupants",
+                        room.name
+                    ))
+                }
+                None => {
+                    self.state.capacities.remove(&room);
+                    NotificationLog::new(format!("{} no longer has a capacity", room.name))
+                }
+            }
+        };
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
+            },
+            vec![user.clone()],
+        ));
+        event_buf.extend(self.admit_waiting(&room));
+    }
+
+    /// Renames the user unless the name is reserved or already online. The user's
+    /// session is sent the renamed User and their room hears about the change.
+    fn handle_rename(
+        &mut self,
+        user: &User,
+        new_name: String,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let refusal = if is_reserved_name(&new_name) && !user.is_admin {
+            Some(format!("The name {new_name} is reserved"))
+        } else if self.state.find_user_by_name(&new_name).is_some() {
+            Some(format!("The name {new_name} is already taken"))
+        } else {
+            None
+        };
+        if let Some(refusal) = refusal {
+            event_buf.push_back(Broadcast::new(
+                Event::Notify {
+                    notice: vec![NotificationLog::new(refusal).with_severity(Severity::Error)],
+                },
+                vec![user.clone()],
+            ));
+            return;
+        }
+
+        let renamed = self.state.rename_user(user, &new_name);
+        let notice = NotificationLog::new(format!("{} is now known as {new_name}", user.name));
+        self.state.record_notification(&renamed, notice.clone());
+        let room = self.state.get_occupied_room(&renamed).unwrap_or_default();
+        event_buf.push_back(Broadcast::new(
+            Event::Renamed {
+                old: user.clone(),
+                new: renamed.clone(),
+            },
+            vec![renamed.clone()],
+        ));
+        let mut subscribers = self.state.room_subscribers(&room);
+        if !subscribers.contains(&renamed) {
+            subscribers.push(renamed);
+        }
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
+            },
+            subscribers,
+        ));
+    }
+
+    /// Replaces the contents of one of the user's own messages in their room's
+    /// history and resends it to the room.
+    fn handle_edit_message(
+        &mut self,
+        user: &User,
+        message_id: u64,
+        new_contents: String,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let max_message_bytes = self.state.max_message_bytes;
+        let found = self
+            .state
+            .chat_logs
+            .get_mut(&room)
+            .and_then(|logs| logs.iter_mut().find(|msg| msg.id == message_id));
+        let result = match found {
+            None => Err((
+                ErrorCode::NotFound,
+                format!("Message {message_id} is not in {}", room.name),
+            )),
+            Some(msg) if msg.author_id != user.id => Err((
+                ErrorCode::Forbidden,
+                "You can only edit your own messages".to_string(),
+            )),
+            Some(_) if new_contents.len() > max_message_bytes => Err((
+                ErrorCode::MessageTooLong,
+                format!(
+                    "Edit not saved, it is {} bytes and the limit is {max_message_bytes}",
+                    new_contents.len()
+                ),
+            )),
+            Some(msg) => {
+                msg.contents = sanitize_contents(&new_contents);
+                Ok(msg.clone())
+            }
+        };
+
+        let broadcast = match result {
+            Ok(msg) => Broadcast::new(
+                Event::MessageEdited { msg },
+                self.state.room_subscribers(&room),
+            ),
+            Err((code, message)) => {
+                Broadcast::new(Event::Error { code, message }, vec![user.clone()])
+            }
+        };
+        event_buf.push_back(broadcast);
+    }
+
+    /// Removes a message from the user's room history and tells the room to drop
+    /// it. Authors may delete their own messages, admins anyone's. A message that
+    /// has already left the history is only acknowledged.
+    fn handle_delete_message(
+        &mut self,
+        user: &User,
+        message_id: u64,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let room = self.state.get_occupied_room(user).unwrap_or_default();
+        let Some(logs) = self.state.chat_logs.get_mut(&room) else {
+            return;
+        };
+        let broadcast = match logs.iter().position(|msg| msg.id == message_id) {
+            None => Broadcast::new(
+                Event::Notify {
+                    notice: vec![NotificationLog::new(format!(
+                        "Message {message_id} is no longer in {}",
+                        room.name
+                    ))],
+                },
+                vec![user.clone()],
+            ),
+            Some(index) if logs[index].author_id != user.id && !user.is_admin => Broadcast::new(
+                Event::Error {
+                    code: ErrorCode::Forbidden,
+                    message: "You can only delete your own messages".into(),
+                },
+                vec![user.clone()],
+            ),
+            Some(index) => {
+                logs.remove(index);
+                Broadcast::new(
+                    Event::MsgDeleted { id: message_id },
+                    self.state.room_subscribers(&room),
+                )
+            }
+        };
+        event_buf.push_back(broadcast);
+    }
+
+    /// Anyone may create a room, as moving into a new one does, but only admins may
+    /// give it a capacity or make it read only from the start.
+    fn handle_create_room(
+        &mut self,
+        user: &User,
+        room: Room,
+        capacity: Option<usize>,
+        read_only: bool,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let rejection = if self.state.occupancy.contains_key(&room) {
+            Err(format!("{} already exists", room.name))
+        } else if (capacity.is_some() || read_only) && !user.is_admin {
+            Err("Only admins can set a capacity or make a room read only".into())
+        } else {
+            self.state.can_create_room(user)
+        };
+        let notice = match rejection {
+            Err(reason) => NotificationLog::new(reason).with_severity(Severity::Error),
+            Ok(()) => {
+                self.state.create_room(&room);
+                self.state.created_rooms.insert(room.clone());
+                self.state
+                    .room_creators
+                    .insert(room.clone(), user.id.clone());
+                if let Some(capacity) = capacity {
+                    self.state.capacities.insert(room.clone(), capacity);
+                }
+                if read_only {
+                    self.state.read_only_rooms.insert(room.clone());
+                }
+                NotificationLog::new(format!("Created {}", room.name))
+            }
+        };
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
+            },
+            vec![user.clone()],
+        ));
+    }
+
+    /// Sends everyone in the room back to the Hub and forgets the room and its
+    /// settings. The Hub itself cannot be closed.
+    fn handle_close_room(&mut self, user: &User, room: Room, event_buf: &mut VecDeque<Broadcast>) {
+        let rejection = if room == Room::default() {
+            Some(format!("{} cannot be closed", room.name))
+        } else if !self.state.occupancy.contains_key(&room) {
+            Some(format!("{} does not exist", room.name))
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            event_buf.push_back(Broadcast::new(
+                Event::Notify {
+                    notice: vec![NotificationLog::new(reason).with_severity(Severity::Error)],
+                },
+                vec![user.clone()],
+            ));
+            return;
+        }
+
+        let occupants = self.state.room_subscribers(&room);
+        for occupant in &occupants {
+            event_buf.extend(self.remove_occupant(occupant));
+            event_buf.extend(self.insert_occupant(occupant, &Room::default()));
+        }
+        self.state.remove_room(&room);
+
+        let mut recipients = occupants;
+        if !recipients.contains(user) {
+            recipients.push(user.clone());
+        }
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![NotificationLog::new(format!("{} was closed", room.name))],
+            },
+            recipients,
+        ));
+    }
+
+    /// Tells each room which messages were pushed out of its history, so clients
+    /// can drop them too. Only used when MARAIN_ANNOUNCE_EVICTIONS is "true".
+    fn announce_evictions(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        for (room, id) in std::mem::take(&mut self.state.evicted) {
+            event_buf.push_back(Broadcast::new(
+                Event::MsgDeleted { id },
+                self.state.room_subscribers(&room),
+            ));
+        }
+    }
+
+    /// Removes expired messages and tells each room which ones to drop, and removes
+    /// users whose place has been held past the occupancy grace period.
+    fn sweep_expired_messages(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        for user in self.state.expire_disconnected(Utc::now()) {
+            event_buf.extend(self.remove_occupant(&user));
+        }
+        self.state.expire_pages(Utc::now());
+        for (room, ids) in self.state.expire_messages(Utc::now()) {
+            let subscribers = self.state.room_subscribers(&room);
+            for id in ids {
+                event_buf.push_back(Broadcast::new(
+                    Event::MsgDeleted { id },
+                    subscribers.clone(),
+                ));
+            }
+        }
+    }
+
+    /// Moves every occupant of one room into another. If the target has a capacity,
+    /// occupants are moved until it is full and the rest stay where they are.
+    fn handle_move_all(
+        &mut self,
+        user: &User,
+        from: Room,
+        to: Room,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let rejection = if from == to {
+            Some(format!("{} is already {}", from.name, to.name))
+        } else if !self.state.occupancy.contains_key(&from) {
+            Some(format!("{} does not exist", from.name))
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            event_buf.push_back(Broadcast::new(
+                Event::Notify {
+                    notice: vec![NotificationLog::new(reason).with_severity(Severity::Error)],
+                },
+                vec![user.clone()],
+            ));
+            return;
+        }
+
+        if !self.state.occupancy.contains_key(&to) {
+            self.state.room_creators.insert(to.clone(), user.id.clone());
+        }
+
+        // Snapshot the occupants first, each move mutates the source room.
+        let mut moving = self.state.room_subscribers(&from);
+        let mut staying = vec![];
+        for occupant in std::mem::take(&mut moving) {
+            if self.state.room_is_full(&to) {
+                staying.push(occupant);
+                continue;
+            }
+            event_buf.extend(self.remove_occupant(&occupant));
+            event_buf.extend(self.insert_occupant(&occupant, &to));
+            moving.push(occupant);
+        }
+        if !staying.is_empty() {
+            event_buf.push_back(Broadcast::new(
+                Event::Notify {
+                    notice: vec![NotificationLog::new(format!(
+                        "{} is full, {} occupants stayed in {}",
+                        to.name,
+                        staying.len(),
+                        from.name
+                    ))
+                    .with_severity(Severity::Warning)],
+                },
+                staying.into_iter().chain([user.clone()]).collect(),
+            ));
+        }
+
+        let mut recipients = moving;
+        if !recipients.contains(user) {
+            recipients.push(user.clone());
+        }
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![NotificationLog::new(format!(
+                    "{} merged into {}",
+                    from.name, to.name
+                ))],
+            },
+            recipients,
+        ));
+    }
+
+    /// Pages go straight to an online user. One who has left but can still resume
+    /// gets them when they come back, anyone else cannot be paged. Pages from
+    /// senders the target ignores are dropped without telling the sender.
+    fn handle_page(
+        &mut self,
+        user: &User,
+        target_name: String,
+        message: String,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let page = NotificationLog::from_user(user, format!("{} paged you: {message}", user.name));
+        // A user whose place is being held has no session to deliver to, so their
+        // page is held like anyone else's who has left.
+        let online = self
+            .state
+            .find_user_by_name(&target_name)
+            .filter(|target| !self.state.disconnected.contains_key(target));
+        if let Some(target) = online {
+            if !self.state.ignores(&target, user) {
+                event_buf.push_back(Broadcast::new(
+                    Event::Notify { notice: vec![page] },
+                    vec![target],
+                ));
+            }
+            return;
+        }
+        let notice = match self.state.find_departed_by_name(&target_name) {
+            None => NotificationLog::new(format!("{target_name} is not online"))
+                .with_severity(Severity::Warning),
+            Some(target) => {
+                // An ignored sender is told the page is held like anyone else.
+                let held =
+                    self.state.ignores(&target, user) || self.state.store_page(&target, page);
+                if held {
+                    NotificationLog::new(format!(
+                        "{target_name} is not online, they will receive your page when they next log in"
+                    ))
+                } else {
+                    NotificationLog::new(format!(
+                        "Could not hold your page for {target_name}, too many pages are waiting"
+                    ))
+                    .with_severity(Severity::Warning)
+                }
+            }
+        };
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
+            },
+            vec![user.clone()],
+        ));
+    }
+
+    fn handle_ignore(
+        &mut self,
+        user: &User,
+        target_name: String,
+        ignore: bool,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) {
+        let target = self
+            .state
+            .find_user_by_name(&target_name)
+            .or_else(|| self.state.find_departed_by_name(&target_name));
+        let notice = match target {
+            None => NotificationLog::new(format!("{target_name} is not online"))
+                .with_severity(Severity::Warning),
+            Some(target) => {
+                let ignored = self.state.ignored.entry(user.id.clone()).or_default();
+                if ignore {
+                    ignored.insert(target.id);
+                    NotificationLog::new(format!("Ignoring pages from {target_name}"))
+                } else {
+                    ignored.remove(&target.id);
+                    NotificationLog::new(format!("No longer ignoring pages from {target_name}"))
+                }
+            }
+        };
+        event_buf.push_back(Broadcast::new(
+            Event::Notify {
+                notice: vec![notice],
+            },
+            vec![user.clone()],
+        ));
+    }
+
+    fn handle_drop_user(&mut self, user: &User, event_buf: &mut VecDeque<Broadcast>) {
+        self.state.leave_waitlists(user);
+        self.state.previous_rooms.remove(user);
+        self.state.sent_messages.remove(&user.id);
+        self.state.last_typing.remove(&user.id);
+        if let Some(room) = self.state.get_occupied_room(user) {
+            self.state.record_departure(user, room);
+            if self.state.occupancy_grace > Duration::zero() {
+                // The room is not told, they keep their place until the sweep runs
+                // out their grace period or they come back for it.
+                self.state.disconnected.insert(user.clone(), Utc::now());
+                event_buf.push_back(Broadcast::new(
+                    Event::UserLeft {
+                        user: user.clone(),
+                        room: Room::default(),
+                        msg_log: vec![],
+                        notifications: vec![],
+                        occupant_names: vec![],
+                    },
+                    vec![user.clone()],
+                ));
+                return;
+            }
+        }
+
+        let mut broadcasts = self.remove_occupant(&user);
+        match broadcasts.first_mut() {
+            // The leaving session waits for its own UserLeft before it shuts down.
+            Some(left) => left.subscribers.push(user.clone()),
+            // They were in no room, as after a kick, so only they need an answer.
+            // Anyone else would take the empty snapshot as their room's contents.
+            None => broadcasts.push(Broadcast {
+                event: Event::UserLeft {
+                    user: user.clone(),
+                    room: Room::default(),
+                    msg_log: vec![],
+                    notifications: vec![],
+                    occupant_names: vec![],
+                },
+                subscribers: vec![user.clone()],
+            }),
+        }
+        event_buf.extend(broadcasts);
+    }
+
+    fn register_user(&mut self, user: User) -> Broadcast {
         Broadcast::new(
+            Event::UserRegistered {
+                token: user.id.clone(),
+            },
+            vec![user.clone()],
+        )
+    }
+
+    /// Removes the user from their room. The room gets the UserLeft snapshot and a
+    /// live notice, nothing is produced if the user was in no room.
+    fn remove_occupant(&mut self, user: &User) -> Vec<Broadcast> {
+        let Some(current_room) = self.state.get_occupied_room(user) else {
+            return vec![];
+        };
+        let notice = NotificationLog::new(format!("{} left {}", user.name, current_room.name));
+
+        self.state.remove_user_from_room(user, notice.clone());
+        let subscribers = self.state.room_subscribers(&current_room);
+        let mut broadcasts = vec![
+            Broadcast::new(
+                Event::UserLeft {
+                    user: user.clone(),
+                    room: current_room.clone(),
+                    occupant_names: self.state.occupant_names(&current_room),
+                    notifications: self.state.room_notifications(&current_room),
+                    msg_log: self.state.room_chat_logs(&current_room),
+                },
+                subscribers.clone(),
+            ),
+            Broadcast::new(
+                Event::Notify {
+                    notice: vec![notice],
+                },
+                subscribers.clone(),
+            ),
+            Broadcast::new(
+                Event::OccupancyChanged {
+                    occupant_names: self.state.occupant_names(&current_room),
+                    room: current_room.clone(),
+                },
+                subscribers,
+            ),
+        ];
+        // The free place goes to whoever has waited longest, before the room can be
+        // collected for being empty.
+        broadcasts.extend(self.admit_waiting(&current_room));
+        self.state.collect_if_empty(&current_room);
+        broadcasts
+    }
+
+    /// Joins the user to the room, tells the other occupants, then sends the next
+    /// banner to the joining user alone.
+    fn insert_occupant(&mut self, user: &User, room: &Room) -> Vec<Broadcast> {
+        self.state.add_user_to_room(user, &room);
+        let notice = NotificationLog::new(format!("{} joined {}", user.name, room.name));
+        self.state.record_notification(user, notice.clone());
+        let others: Vec<User> = self
+            .state
+            .room_subscribers(room)
+            .into_iter()
+            .filter(|occupant| occupant != user)
+            .collect();
+        let mut broadcasts = vec![Broadcast::new(
             Event::UserJoined {
                 user: user.clone(),
                 room: room.clone(),
@@ -308,22 +2114,89 @@ impl CommandHandler {
                 occupant_names: self.state.occupant_names(room),
             },
             self.state.room_subscribers(&room),
-        )
+        )];
+        // Occupants already there hear about the arrival straight away, the joiner
+        // has the occupant list in their UserJoined snapshot.
+        if !others.is_empty() {
+            broadcasts.push(Broadcast::new(
+                Event::Notify {
+                    notice: vec![notice],
+                },
+                others.clone(),
+            ));
+            broadcasts.push(Broadcast::new(
+                Event::OccupancyChanged {
+                    room: room.clone(),
+                    occupant_names: self.state.occupant_names(room),
+                },
+                others,
+            ));
+        }
+        if let Some(banner) = self.state.next_banner() {
+            let occupant_count = self.state.room_subscribers(room).len();
+            broadcasts.push(Broadcast::new(
+                Event::Notify {
+                    notice: vec![NotificationLog::new(render_banner(
+                        &banner,
+                        user,
+                        room,
+                        occupant_count,
+                    ))],
+                },
+                vec![user.clone()],
+            ));
+        }
+        broadcasts
     }
 }
 
 pub struct App {
-    gateway_source: UnboundedReceiver<Command>,
+    gateway_source: Receiver<Command>,
+    shutdown_sink: UnboundedSender<()>,
+    shutdown_source: UnboundedReceiver<()>,
     command_handler: CommandHandler,
     event_bus: EventBus,
+    stats_period: Option<std::time::Duration>,
+    consistency_period: Option<std::time::Duration>,
+    repair_state: bool,
+    started_at: Instant,
 }
 
 impl App {
-    pub fn init(command_source: UnboundedReceiver<Command>) -> Self {
+    pub fn init(command_source: Receiver<Command>) -> Self {
+        let (shutdown_sink, shutdown_source) = unbounded();
         Self {
             gateway_source: command_source,
+            shutdown_sink,
+            shutdown_source,
             command_handler: CommandHandler::new(AppState::new()),
             event_bus: EventBus::new(),
+            stats_period: App::period_from_env("MARAIN_STATS_INTERVAL_SECS"),
+            consistency_period: App::period_from_env("MARAIN_CONSISTENCY_CHECK_SECS"),
+            repair_state: getenv("MARAIN_CONSISTENCY_REPAIR") == "true",
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Sending on this tells every subscribed session that the server is shutting
+    /// down, which ends the session and drops the user as usual.
+    pub fn shutdown_sink(&self) -> UnboundedSender<()> {
+        self.shutdown_sink.clone()
+    }
+
+    /// Reads a number of seconds from the environment, treating unset or zero as disabled.
+    fn period_from_env(name: &str) -> Option<std::time::Duration> {
+        match getenv(name).parse::<u64>() {
+            Ok(secs) if secs > 0 => Some(std::time::Duration::from_secs(secs)),
+            _ => None,
+        }
+    }
+
+    /// Resolves on the next tick of an optional interval, or never if it is disabled.
+    async fn tick(interval: &mut Option<Interval>) -> Instant {
+        match interval {
+            Some(interval) => interval.tick().await,
+            None => std::future::pending().await,
         }
     }
 
@@ -338,42 +2211,1999 @@ impl App {
 
     pub async fn work(&mut self) -> Result<()> {
         let mut event_buf: VecDeque<Broadcast> = VecDeque::new();
-        let mut defer_unsubscribe: Option<User> = None;
+        let mut stats_interval = self.stats_period.map(interval);
+        let mut consistency_interval = self.consistency_period.map(interval);
+        let mut expiry_interval = interval(MESSAGE_EXPIRY_SWEEP);
 
-        while let Some(command) = self.gateway_source.next().await {
-            match command.clone() {
-                Command {
-                    user,
-                    payload: CommandPayload::RegisterUser(delivery_channel, ..),
-                } => self.event_bus.subscribe(user, delivery_channel),
-                Command {
-                    user,
-                    payload: CommandPayload::DropUser,
-                } => {
-                    defer_unsubscribe = Some(user.clone());
-                    Ok(())
-                }
-                _ => Ok(()),
-            }?;
-            match self.command_handler.handle(command, &mut event_buf) {
-                Ok(_) => {
-                    while let Some(cast) = event_buf.pop_front() {
-                        self.event_bus.publish(&cast);
+        loop {
+            tokio::select! {
+                command = self.gateway_source.next() => match command {
+                    Some(command) => {
+                        self.process_command(command, &mut event_buf)?;
+                        metrics::COMMANDS_HANDLED.increment();
                     }
+                    None => return Ok(()),
+                },
+                _ = App::tick(&mut stats_interval) => self.publish_server_stats(),
+                _ = App::tick(&mut consistency_interval) => self.check_consistency(),
+                _ = expiry_interval.tick() => self.sweep_expired_messages(&mut event_buf),
+                Some(()) = self.shutdown_source.next() => self.publish_shutdown(),
+            }
+            self.drop_dead_subscribers(&mut event_buf)?;
+            metrics::ROOMS.set(self.command_handler.state.occupancy.len() as u64);
+        }
+    }
+
+    fn process_command(
+        &mut self,
+        command: Command,
+        event_buf: &mut VecDeque<Broadcast>,
+    ) -> Result<()> {
+        // Ping reports on the App itself rather than the room state, but is still
+        // subject to the permissions file.
+        if let CommandPayload::Ping = command.payload {
+            match self.command_handler.refuse_command(&command) {
+                Some(refusal) => self.event_bus.publish(&refusal),
+                None => self.publish_pong(command.user),
+            }
+            return Ok(());
+        }
+        let mut defer_unsubscribe: Option<User> = None;
+
+        match command.clone() {
+            Command {
+                user,
+                payload: CommandPayload::RegisterUser(delivery_channel, generation),
+            } => self.event_bus.subscribe(user, delivery_channel, generation),
+            Command {
+                user,
+                payload: CommandPayload::DropUser(generation),
+            } => {
+                if self.event_bus.superseded(&user, generation) {
+                    log::info!(
+                        "Ignoring a drop for {user:?} from a session that was resumed elsewhere"
+                    );
+                    return Ok(());
                 }
-                Err(e) => {
-                    return Err(e);
+                defer_unsubscribe = Some(user.clone())
+            }
+            _ => {}
+        };
+        let sender = command.user.clone();
+        match self.command_handler.handle(command, event_buf) {
+            Ok(_) => {
+                while let Some(cast) = event_buf.pop_front() {
+                    self.event_bus.publish(&cast);
                 }
             }
-            if let Some(ref user) = defer_unsubscribe {
-                match self.event_bus.unsubscribe(user.clone()) {
-                    Err(e) => panic!("Failed to unsubscribe a user: {user:?} with Error: {e}"),
-                    _ => {}
+            // A failed command is reported to its sender rather than stopping the App.
+            Err(e) => {
+                log::error!("Command from {sender:?} failed: {e}");
+                event_buf.clear();
+                let code = match e {
+                    MarainServerError::Unsupported(_) => ErrorCode::UnknownCommand,
+                    MarainServerError::Forbidden(_) => ErrorCode::Forbidden,
+                    _ => ErrorCode::Internal,
                 };
-                defer_unsubscribe = None;
+                let error = Event::Error {
+                    code,
+                    message: e.to_string(),
+                };
+                self.event_bus.publish(&Broadcast::new(error, vec![sender]));
             }
         }
+        if let Some(ref user) = defer_unsubscribe {
+            // The user may already have been dropped after their channel closed.
+            match self.event_bus.unsubscribe(user.clone()) {
+                Err(e) => log::warn!("Failed to unsubscribe a user: {user:?} with Error: {e}"),
+                _ => {}
+            };
+        }
+
+        Ok(())
+    }
 
+    /// Drops every user whose channel was found closed while publishing, as if
+    /// they had sent DropUser. They are unsubscribed first so nothing more is
+    /// sent to them, and the UserLeft broadcasts may turn up further dead users.
+    fn drop_dead_subscribers(&mut self, event_buf: &mut VecDeque<Broadcast>) -> Result<()> {
+        while let Some(user) = self.event_bus.dead.pop() {
+            let Ok(generation) = self.event_bus.unsubscribe(user.clone()) else {
+                continue;
+            };
+            let drop = Command {
+                user,
+                payload: CommandPayload::DropUser(generation),
+            };
+            self.command_handler.handle(drop, event_buf)?;
+            while let Some(cast) = event_buf.pop_front() {
+                self.event_bus.publish(&cast);
+            }
+        }
         Ok(())
     }
+
+    fn sweep_expired_messages(&mut self, event_buf: &mut VecDeque<Broadcast>) {
+        self.command_handler.sweep_expired_messages(event_buf);
+        while let Some(cast) = event_buf.pop_front() {
+            self.event_bus.publish(&cast);
+        }
+    }
+
+    fn publish_pong(&mut self, user: User) {
+        let pong = Event::Pong {
+            server_version: env!("CARGO_PKG_VERSION").into(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            active_connections: self.event_bus.subscribers.len(),
+        };
+        self.event_bus.publish(&Broadcast::new(pong, vec![user]));
+    }
+
+    fn publish_shutdown(&mut self) {
+        log::info!(
+            "Telling {} sessions the server is shutting down",
+            self.event_bus.subscribers.len()
+        );
+        let everyone = self.event_bus.subscribers.keys().cloned().collect();
+        self.event_bus
+            .publish(&Broadcast::new(Event::ServerShutdown, everyone));
+    }
+
+    /// Pushes a snapshot of server activity to every connected admin.
+    fn publish_server_stats(&mut self) {
+        let admins: Vec<User> = self
+            .event_bus
+            .subscribers
+            .keys()
+            .filter(|user| user.is_admin)
+            .cloned()
+            .collect();
+        if admins.is_empty() {
+            return;
+        }
+
+        let state = &mut self.command_handler.state;
+        let stats = Event::ServerStats {
+            connections: self.event_bus.subscribers.len(),
+            rooms: state.occupancy.len(),
+            messages_per_min: state.messages_per_min(),
+        };
+        self.event_bus.publish(&Broadcast::new(stats, admins));
+    }
+
+    /// Scans the parallel occupancy, log and subscriber maps for drift and logs
+    /// anything that disagrees. Rooms missing a log are given an empty one when
+    /// MARAIN_CONSISTENCY_REPAIR is "true", everything else is only reported.
+    fn check_consistency(&mut self) {
+        let state = &mut self.command_handler.state;
+
+        for user in self.event_bus.subscribers.keys() {
+            let rooms = state
+                .occupancy
+                .values()
+                .filter(|occupants| occupants.contains(user))
+                .count();
+            match rooms {
+                0 => log::warn!("Consistency: subscriber {user:?} is not in any room"),
+                1 => {}
+                n => log::warn!("Consistency: subscriber {user:?} is in {n} rooms"),
+            }
+        }
+
+        for (room, occupants) in &state.occupancy {
+            for occupant in occupants {
+                if !self.event_bus.subscribers.contains_key(occupant) {
+                    log::warn!("Consistency: {occupant:?} occupies {room:?} but is not subscribed");
+                }
+            }
+        }
+
+        let rooms: Vec<Room> = state.occupancy.keys().cloned().collect();
+        for room in rooms {
+            if !state.chat_logs.contains_key(&room) {
+                log::warn!("Consistency: {room:?} has occupancy but no chat log");
+                if self.repair_state {
+                    state.chat_logs.insert(room.clone(), VecDeque::new());
+                }
+            }
+            if !state.notifications.contains_key(&room) {
+                log::warn!("Consistency: {room:?} has occupancy but no notification log");
+                if self.repair_state {
+                    state.notifications.insert(room.clone(), VecDeque::new());
+                }
+            }
+        }
+
+        for room in state.chat_logs.keys().chain(state.notifications.keys()) {
+            if !state.occupancy.contains_key(room) {
+                log::warn!("Consistency: {room:?} has logs but no occupancy entry");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    fn user(name: &str) -> User {
+        User::new(name.into(), name.into(), [0; 32])
+    }
+
+    fn rooms_containing(state: &AppState, user: &User) -> Vec<Room> {
+        state
+            .occupancy
+            .iter()
+            .filter(|(_, occupants)| occupants.contains(user))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    #[test]
+    fn added_user_is_found_in_their_room() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+
+        state.add_user_to_room(&alice, &Room::default());
+
+        assert_eq!(state.get_occupied_room(&alice), Some(Room::default()));
+        assert_eq!(state.occupant_names(&Room::default()), vec!["alice"]);
+    }
+
+    #[test]
+    fn moved_user_occupies_exactly_one_room() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+
+        state.add_user_to_room(&alice, &Room::default());
+        state.remove_user_from_room(&alice, NotificationLog::new("alice left Hub".into()));
+        state.add_user_to_room(&alice, &lobby);
+
+        assert_eq!(rooms_containing(&state, &alice), vec![lobby.clone()]);
+        assert_eq!(state.get_occupied_room(&alice), Some(lobby));
+    }
+
+    #[test]
+    fn inserting_twice_without_removal_keeps_single_occupancy() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&alice, &lobby);
+        handler.insert_occupant(&alice, &lobby);
+
+        assert_eq!(
+            rooms_containing(&handler.state, &alice),
+            vec![lobby.clone()]
+        );
+        assert_eq!(handler.state.room_subscribers(&lobby), vec![alice]);
+    }
+
+    #[test]
+    fn existing_occupants_are_notified_of_arrivals_and_departures() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        let bob = user("bob");
+        handler.insert_occupant(&alice, &Room::default());
+
+        let joined = handler.insert_occupant(&bob, &Room::default());
+        let notice = joined
+            .iter()
+            .find(|b| matches!(b.event, Event::Notify { .. }))
+            .unwrap();
+        assert_eq!(notice.subscribers, vec![alice.clone()]);
+        let occupancy = joined
+            .iter()
+            .find(|b| matches!(b.event, Event::OccupancyChanged { .. }))
+            .unwrap();
+        assert_eq!(occupancy.subscribers, vec![alice.clone()]);
+
+        let left = handler.remove_occupant(&bob);
+        assert!(matches!(left[0].event, Event::UserLeft { .. }));
+        assert!(matches!(left[1].event, Event::Notify { .. }));
+        assert_eq!(left[1].subscribers, vec![alice.clone()]);
+        assert!(matches!(
+            &left[2].event,
+            Event::OccupancyChanged { occupant_names, .. } if *occupant_names == vec!["alice"]
+        ));
+        assert_eq!(left[2].subscribers, vec![alice]);
+    }
+
+    #[test]
+    fn registered_then_dropped_user_leaves_no_occupancy_behind() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        let mut event_buf = VecDeque::new();
+        let command = |payload| Command {
+            user: alice.clone(),
+            payload,
+        };
+
+        handler
+            .handle(
+                command(CommandPayload::RegisterUser(unbounded().0, 0)),
+                &mut event_buf,
+            )
+            .unwrap();
+        assert_eq!(
+            handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+        handler
+            .handle(command(CommandPayload::DropUser(0)), &mut event_buf)
+            .unwrap();
+
+        assert!(handler.state.occupancy.values().all(Vec::is_empty));
+        assert_eq!(handler.state.get_occupied_room(&alice), None);
+    }
+
+    #[test]
+    fn dropping_a_user_in_no_room_answers_only_them() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&bob, &Room::default());
+        let mut event_buf = VecDeque::new();
+
+        let command = Command {
+            user: alice.clone(),
+            payload: CommandPayload::DropUser(0),
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        assert_eq!(event_buf.len(), 1);
+        assert!(matches!(event_buf[0].event, Event::UserLeft { .. }));
+        assert_eq!(event_buf[0].subscribers, vec![alice]);
+    }
+
+    #[test]
+    fn occupants_keep_their_order_when_someone_leaves() {
+        let mut state = AppState::new();
+        let (alice, bob, carol, dave) = (user("alice"), user("bob"), user("carol"), user("dave"));
+        for occupant in [&alice, &bob, &carol, &dave] {
+            state.add_user_to_room(occupant, &Room::default());
+        }
+
+        state.remove_user_from_room(&bob, NotificationLog::new("bob left Hub".into()));
+
+        assert_eq!(
+            state.occupant_names(&Room::default()),
+            vec!["alice", "carol", "dave"]
+        );
+    }
+
+    #[test]
+    fn resumed_users_go_back_to_the_room_they_left() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let lobby = Room::from("lobby");
+        // Someone stays behind, or the room would be collected when alice leaves.
+        handler.insert_occupant(&bob, &lobby);
+        let register = |alice: &User| Command {
+            user: alice.clone(),
+            payload: CommandPayload::RegisterUser(unbounded().0, 0),
+        };
+        let mut event_buf = VecDeque::new();
+        handler.handle(register(&alice), &mut event_buf).unwrap();
+        handler.insert_occupant(&alice, &lobby);
+
+        let drop = Command {
+            user: alice.clone(),
+            payload: CommandPayload::DropUser(0),
+        };
+        handler.handle(drop, &mut event_buf).unwrap();
+        assert_eq!(handler.state.get_occupied_room(&alice), None);
+
+        handler.handle(register(&alice), &mut event_buf).unwrap();
+        assert_eq!(handler.state.get_occupied_room(&alice), Some(lobby));
+
+        // Only once, a later login with the same id starts in the Hub again.
+        handler.remove_occupant(&alice);
+        handler.handle(register(&alice), &mut event_buf).unwrap();
+        assert_eq!(
+            handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+    }
+
+    #[test]
+    fn removing_absent_user_is_a_no_op() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+        let bob = user("bob");
+        state.add_user_to_room(&alice, &Room::default());
+
+        state.remove_user_from_room(&bob, NotificationLog::new("bob left Hub".into()));
+
+        assert_eq!(state.get_occupied_room(&bob), None);
+        assert_eq!(state.room_subscribers(&Room::default()), vec![alice]);
+        assert!(state.room_notifications(&Room::default()).is_empty());
+    }
+
+    #[test]
+    fn chat_logs_never_exceed_max_logs() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+        state.add_user_to_room(&alice, &Room::default());
+
+        for i in 0..state.max_logs + 5 {
+            state.record_chat_message(&alice, MessageLog::from_user(&alice, format!("{i}")));
+        }
+
+        let logs = state.room_chat_logs(&Room::default());
+        assert_eq!(logs.len(), state.max_logs);
+        assert_eq!(logs.first().unwrap().contents, "5");
+    }
+
+    #[test]
+    fn chat_message_recipients_are_the_senders_room() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+        let bob = user("bob");
+        let carol = user("carol");
+        state.add_user_to_room(&alice, &Room::default());
+        state.add_user_to_room(&bob, &Room::default());
+        state.add_user_to_room(&carol, &Room::from("lobby"));
+
+        let recipients =
+            state.record_chat_message(&alice, MessageLog::from_user(&alice, "hi".into()));
+
+        assert_eq!(recipients, Some(&[alice, bob][..]));
+        assert!(state.room_chat_logs(&Room::from("lobby")).is_empty());
+    }
+
+    #[test]
+    fn chat_message_from_user_in_no_room_is_not_recorded() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+
+        let recipients =
+            state.record_chat_message(&alice, MessageLog::from_user(&alice, "hi".into()));
+
+        assert!(recipients.is_none());
+        assert!(state.room_chat_logs(&Room::default()).is_empty());
+    }
+
+    #[test]
+    fn notifications_are_recorded_in_the_users_room() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+        state.add_user_to_room(&alice, &lobby);
+
+        state.record_notification(&alice, NotificationLog::new("alice joined lobby".into()));
+
+        let notices = state.room_notifications(&lobby);
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].contents, "alice joined lobby");
+        assert!(state.room_notifications(&Room::default()).is_empty());
+    }
+
+    #[test]
+    fn move_all_empties_the_source_room() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        let lobby = Room::from("lobby");
+        for name in ["alice", "bob", "carol"] {
+            handler.insert_occupant(&user(name), &Room::default());
+        }
+
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: admin,
+            payload: CommandPayload::MoveAll {
+                from: Room::default(),
+                to: lobby.clone(),
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        assert!(handler.state.room_subscribers(&Room::default()).is_empty());
+        assert_eq!(
+            handler.state.occupant_names(&lobby),
+            vec!["alice", "bob", "carol"]
+        );
+    }
+
+    #[test]
+    fn sender_receives_their_message_with_server_timestamp() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        let before = Utc::now();
+
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: alice.clone(),
+            payload: CommandPayload::RecordMessage {
+                message: "hello".into(),
+                reply_to: None,
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        let broadcast = event_buf.pop_front().unwrap();
+        assert_eq!(broadcast.subscribers, vec![alice]);
+        let Event::MsgReceived { msg } = broadcast.event else {
+            panic!("expected the message to be echoed");
+        };
+        assert!(msg.timestamp >= before && msg.timestamp <= Utc::now());
+        assert_eq!(msg.username, "alice");
+    }
+
+    #[test]
+    fn message_sent_before_joining_is_refused() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: alice.clone(),
+            payload: CommandPayload::RecordMessage {
+                message: "too early".into(),
+                reply_to: None,
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        let broadcast = event_buf.pop_front().unwrap();
+        assert_eq!(broadcast.subscribers, vec![alice]);
+        assert!(matches!(
+            broadcast.event,
+            Event::Error {
+                code: ErrorCode::NotFound,
+                ..
+            }
+        ));
+        assert!(event_buf.is_empty());
+    }
+
+    fn reply(handler: &mut CommandHandler, sender: &User, parent: u64) -> MessageLog {
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: sender.clone(),
+            payload: CommandPayload::RecordMessage {
+                message: "agreed".into(),
+                reply_to: Some(parent),
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+        match event_buf.pop_front().unwrap().event {
+            Event::MsgReceived { msg } => msg,
+            _ => panic!("expected the reply to be broadcast"),
+        }
+    }
+
+    #[test]
+    fn reply_to_message_in_room_references_parent() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        let parent = MessageLog::from_user(&alice, "lunch?".into());
+        handler.state.record_chat_message(&alice, parent.clone());
+
+        let msg = reply(&mut handler, &alice, parent.id);
+
+        assert_eq!(
+            msg.reply_to,
+            Some(ReplyTo {
+                id: parent.id,
+                available: true
+            })
+        );
+    }
+
+    #[test]
+    fn reply_to_unknown_message_is_delivered_as_unavailable() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+
+        let msg = reply(&mut handler, &alice, u64::MAX);
+
+        assert_eq!(
+            msg.reply_to,
+            Some(ReplyTo {
+                id: u64::MAX,
+                available: false
+            })
+        );
+        assert_eq!(
+            handler
+                .state
+                .room_chat_logs(&Room::default())
+                .last()
+                .unwrap()
+                .id,
+            msg.id
+        );
+    }
+
+    #[test]
+    fn messages_past_their_ttl_are_swept() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        handler
+            .state
+            .message_ttls
+            .insert(Room::default(), Duration::seconds(60));
+        let mut old = MessageLog::from_user(&alice, "old".into());
+        old.timestamp = Utc::now() - Duration::seconds(120);
+        let fresh = MessageLog::from_user(&alice, "fresh".into());
+        handler.state.record_chat_message(&alice, old.clone());
+        handler.state.record_chat_message(&alice, fresh.clone());
+
+        let mut event_buf = VecDeque::new();
+        handler.sweep_expired_messages(&mut event_buf);
+
+        let remaining: Vec<u64> = handler
+            .state
+            .room_chat_logs(&Room::default())
+            .iter()
+            .map(|msg| msg.id)
+            .collect();
+        assert_eq!(remaining, vec![fresh.id]);
+        let broadcast = event_buf.pop_front().unwrap();
+        assert!(matches!(broadcast.event, Event::MsgDeleted { id } if id == old.id));
+        assert_eq!(broadcast.subscribers, vec![alice]);
+        assert!(event_buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn message_sent_straight_after_registering_is_delivered() {
+        let (mut app_sink, gateway_source) = futures_channel::mpsc::channel(16);
+        App::init(gateway_source).run();
+        let (event_sink, mut events) = futures_channel::mpsc::unbounded();
+        let alice = user("alice");
+
+        for payload in [
+            CommandPayload::RegisterUser(event_sink, 0),
+            CommandPayload::RecordMessage {
+                message: "first!".into(),
+                reply_to: None,
+            },
+        ] {
+            app_sink
+                .try_send(Command {
+                    user: alice.clone(),
+                    payload,
+                })
+                .unwrap();
+        }
+
+        let delivered = tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while let Some(event) = events.next().await {
+                if let Event::MsgReceived { msg } = event {
+                    return Some(msg);
+                }
+            }
+            None
+        })
+        .await
+        .unwrap();
+        assert_eq!(delivered.unwrap().contents, "first!");
+    }
+
+    #[test]
+    fn subscribing_twice_keeps_only_the_newest_channel() {
+        let mut event_bus = EventBus::new();
+        let alice = user("alice");
+        let (stale_sink, mut stale_events) = futures_channel::mpsc::unbounded();
+        let (live_sink, mut live_events) = futures_channel::mpsc::unbounded();
+
+        event_bus.subscribe(alice.clone(), stale_sink, 1);
+        event_bus.subscribe(alice.clone(), live_sink, 2);
+        event_bus.publish(&Broadcast::new(Event::MsgDeleted { id: 1 }, vec![alice]));
+
+        assert_eq!(event_bus.subscribers.len(), 1);
+        assert!(matches!(
+            live_events.next().now_or_never(),
+            Some(Some(Event::MsgDeleted { id: 1 }))
+        ));
+        assert!(matches!(stale_events.next().now_or_never(), Some(None)));
+        assert!(event_bus.dead.is_empty());
+    }
+
+    #[test]
+    fn drop_from_a_taken_over_session_leaves_the_resumed_one_alone() {
+        let (_app_sink, gateway_source) = futures_channel::mpsc::channel(16);
+        let mut app = App::init(gateway_source);
+        let mut event_buf = VecDeque::new();
+        let (alice, bob) = (user("alice"), user("bob"));
+        let mut resumed = alice.clone();
+        resumed.is_admin = true;
+        let (stale_sink, _stale_events) = futures_channel::mpsc::unbounded();
+        let (live_sink, mut live_events) = futures_channel::mpsc::unbounded();
+        let (bob_sink, _bob_events) = futures_channel::mpsc::unbounded();
+        let commands = [
+            (alice.clone(), CommandPayload::RegisterUser(stale_sink, 1)),
+            (bob.clone(), CommandPayload::RegisterUser(bob_sink, 2)),
+            (resumed.clone(), CommandPayload::RegisterUser(live_sink, 3)),
+            // The stale session missed the takeover and still drops its user.
+            (alice.clone(), CommandPayload::DropUser(1)),
+            (
+                bob,
+                CommandPayload::RecordMessage {
+                    message: "still there?".into(),
+                    reply_to: None,
+                },
+            ),
+        ];
+        for (user, payload) in commands {
+            app.process_command(Command { user, payload }, &mut event_buf)
+                .unwrap();
+        }
+
+        let mut received = None;
+        while let Some(Some(event)) = live_events.next().now_or_never() {
+            if let Event::MsgReceived { msg } = event {
+                received = Some(msg.contents);
+            }
+        }
+        assert_eq!(received.as_deref(), Some("still there?"));
+        assert_eq!(
+            app.command_handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+        // The subscription carries the resumed session's user, not the stale one.
+        let (subscriber, _) = app.event_bus.subscribers.get_key_value(&alice).unwrap();
+        assert!(subscriber.is_admin);
+    }
+
+    #[test]
+    fn messages_still_reach_a_renamed_user() {
+        let (_app_sink, gateway_source) = futures_channel::mpsc::channel(16);
+        let mut app = App::init(gateway_source);
+        let mut event_buf = VecDeque::new();
+        let alice = user("alice");
+        let bob = user("bob");
+        let (alice_sink, mut alice_events) = futures_channel::mpsc::unbounded();
+        let (bob_sink, _bob_events) = futures_channel::mpsc::unbounded();
+        for (user, sink) in [(alice.clone(), alice_sink), (bob.clone(), bob_sink)] {
+            let register = Command {
+                user,
+                payload: CommandPayload::RegisterUser(sink, 0),
+            };
+            app.process_command(register, &mut event_buf).unwrap();
+        }
+
+        let rename = Command {
+            user: alice.clone(),
+            payload: CommandPayload::Rename {
+                new_name: "alicia".into(),
+            },
+        };
+        app.process_command(rename, &mut event_buf).unwrap();
+        let message = Command {
+            user: bob,
+            payload: CommandPayload::RecordMessage {
+                message: "hi alicia".into(),
+                reply_to: None,
+            },
+        };
+        app.process_command(message, &mut event_buf).unwrap();
+
+        let mut renamed = None;
+        let mut received = None;
+        while let Some(Some(event)) = alice_events.next().now_or_never() {
+            match event {
+                Event::Renamed { new, .. } => renamed = Some(new),
+                Event::MsgReceived { msg } => received = Some(msg.contents),
+                _ => {}
+            }
+        }
+        let renamed = renamed.unwrap();
+        assert_eq!(renamed.name, "alicia");
+        assert_eq!(received.as_deref(), Some("hi alicia"));
+        let (subscriber, _) = app.event_bus.subscribers.get_key_value(&alice).unwrap();
+        assert_eq!(subscriber.name, "alicia");
+        assert_eq!(
+            app.command_handler.state.get_occupied_room(&renamed),
+            Some(Room::default())
+        );
+    }
+
+    #[test]
+    fn rename_to_an_online_name_is_refused() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&user("bob"), &Room::default());
+        let mut event_buf = VecDeque::new();
+
+        handler.handle_rename(&alice, "bob".into(), &mut event_buf);
+
+        assert_eq!(
+            handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+        assert!(!event_buf
+            .iter()
+            .any(|b| matches!(b.event, Event::Renamed { .. })));
+    }
+
+    #[test]
+    fn denied_ping_is_refused_instead_of_answered() {
+        let (_app_sink, gateway_source) = futures_channel::mpsc::channel(16);
+        let mut app = App::init(gateway_source);
+        app.command_handler.state.permissions =
+            Permissions::from_toml("[commands]\nping = []").unwrap();
+        let mut event_buf = VecDeque::new();
+        let alice = user("alice");
+        let (alice_sink, mut alice_events) = futures_channel::mpsc::unbounded();
+        let register = Command {
+            user: alice.clone(),
+            payload: CommandPayload::RegisterUser(alice_sink, 0),
+        };
+        app.process_command(register, &mut event_buf).unwrap();
+
+        let ping = Command {
+            user: alice,
+            payload: CommandPayload::Ping,
+        };
+        app.process_command(ping, &mut event_buf).unwrap();
+
+        let mut events = vec![];
+        while let Some(Some(event)) = alice_events.next().now_or_never() {
+            events.push(event);
+        }
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::Pong { .. })));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::Error {
+                code: ErrorCode::Forbidden,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn unsupported_command_is_reported_to_its_sender() {
+        let (_app_sink, gateway_source) = futures_channel::mpsc::channel(16);
+        let mut app = App::init(gateway_source);
+        let mut event_buf = VecDeque::new();
+        let alice = user("alice");
+        let (alice_sink, mut alice_events) = futures_channel::mpsc::unbounded();
+        let register = Command {
+            user: alice.clone(),
+            payload: CommandPayload::RegisterUser(alice_sink, 0),
+        };
+        app.process_command(register, &mut event_buf).unwrap();
+
+        // Help is answered by the session, so the App does not handle it.
+        let help = Command {
+            user: alice,
+            payload: CommandPayload::Help,
+        };
+        assert!(app.process_command(help, &mut event_buf).is_ok());
+
+        let mut code = None;
+        while let Some(Some(event)) = alice_events.next().now_or_never() {
+            if let Event::Error { code: c, .. } = event {
+                code = Some(c);
+            }
+        }
+        assert_eq!(code, Some(ErrorCode::UnknownCommand));
+    }
+
+    #[test]
+    fn subscriber_with_a_closed_channel_is_dropped() {
+        let (_app_sink, gateway_source) = futures_channel::mpsc::channel(16);
+        let mut app = App::init(gateway_source);
+        let mut event_buf = VecDeque::new();
+        let alice = user("alice");
+        let bob = user("bob");
+        let (alice_sink, _alice_events) = futures_channel::mpsc::unbounded();
+        let (bob_sink, bob_events) = futures_channel::mpsc::unbounded();
+        for (user, sink) in [(alice.clone(), alice_sink), (bob.clone(), bob_sink)] {
+            let register = Command {
+                user,
+                payload: CommandPayload::RegisterUser(sink, 0),
+            };
+            app.process_command(register, &mut event_buf).unwrap();
+        }
+        drop(bob_events);
+
+        let message = Command {
+            user: alice.clone(),
+            payload: CommandPayload::RecordMessage {
+                message: "anyone there?".into(),
+                reply_to: None,
+            },
+        };
+        app.process_command(message, &mut event_buf).unwrap();
+        app.drop_dead_subscribers(&mut event_buf).unwrap();
+
+        assert!(!app.event_bus.subscribers.contains_key(&bob));
+        assert_eq!(
+            app.command_handler.state.room_subscribers(&Room::default()),
+            vec![alice]
+        );
+    }
+
+    #[test]
+    fn room_data_is_sent_only_to_the_requester_without_moving_them() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        let bob = user("bob");
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let mut event_buf = VecDeque::new();
+        let refresh = |user: &User| Command {
+            user: user.clone(),
+            payload: CommandPayload::GetRoomData,
+        };
+
+        handler.handle(refresh(&alice), &mut event_buf).unwrap();
+        let broadcast = event_buf.pop_front().unwrap();
+        assert_eq!(broadcast.subscribers, vec![alice.clone()]);
+        match broadcast.event {
+            Event::RoomData {
+                room,
+                occupant_names,
+                ..
+            } => {
+                assert_eq!(room, Room::default());
+                assert_eq!(occupant_names, vec!["alice", "bob"]);
+            }
+            _ => panic!("expected RoomData"),
+        }
+
+        handler.insert_occupant(&bob, &lobby);
+        handler.handle(refresh(&bob), &mut event_buf).unwrap();
+        match event_buf.pop_front().unwrap().event {
+            Event::RoomData {
+                room,
+                occupant_names,
+                ..
+            } => {
+                assert_eq!(room, lobby.clone());
+                assert_eq!(occupant_names, vec!["bob"]);
+            }
+            _ => panic!("expected RoomData"),
+        }
+        assert_eq!(handler.state.get_occupied_room(&bob), Some(lobby));
+    }
+
+    #[test]
+    fn swap_room_returns_to_the_previous_room() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &lobby);
+        let mut event_buf = VecDeque::new();
+
+        handler.handle_move_user(&alice, lobby.clone(), true, &mut event_buf);
+        let swap = Command {
+            user: alice.clone(),
+            payload: CommandPayload::SwapRoom,
+        };
+        handler.handle(swap.clone(), &mut event_buf).unwrap();
+        assert_eq!(
+            handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+
+        handler.handle(swap, &mut event_buf).unwrap();
+        assert_eq!(handler.state.get_occupied_room(&alice), Some(lobby));
+    }
+
+    #[test]
+    fn banner_tokens_are_substituted() {
+        let banner = render_banner(
+            "Welcome to {room}, {name}! {occupant_count} here, {unknown} stays.",
+            &user("alice"),
+            &Room::from("lobby"),
+            3,
+        );
+
+        assert_eq!(banner, "Welcome to lobby, alice! 3 here, {unknown} stays.");
+    }
+
+    #[test]
+    fn banner_without_tokens_is_unchanged() {
+        let banner = render_banner("Be nice.", &user("alice"), &Room::default(), 1);
+
+        assert_eq!(banner, "Be nice.");
+    }
+
+    #[test]
+    fn joining_a_new_room_creates_all_room_entries() {
+        let mut state = AppState::new();
+        let lobby = Room::from("lobby");
+
+        state.add_user_to_room(&user("alice"), &lobby);
+
+        assert!(state.occupancy.contains_key(&lobby));
+        assert!(state.chat_logs.contains_key(&lobby));
+        assert!(state.notifications.contains_key(&lobby));
+    }
+
+    #[test]
+    fn moving_into_a_brand_new_room_creates_and_joins_it() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let mut event_buf = VecDeque::new();
+
+        let command = Command {
+            user: alice.clone(),
+            payload: CommandPayload::MoveUser {
+                target_room: lobby.clone(),
+                create_if_missing: true,
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        assert_eq!(handler.state.get_occupied_room(&alice), Some(lobby.clone()));
+        assert_eq!(handler.state.occupant_names(&lobby), vec!["alice"]);
+        let joined = event_buf
+            .iter()
+            .find(|broadcast| {
+                matches!(&broadcast.event, Event::UserJoined { room, .. } if *room == lobby)
+            })
+            .unwrap();
+        assert_eq!(joined.subscribers, vec![alice]);
+    }
+
+    #[test]
+    fn get_recipients_outside_a_room_replies_with_no_one() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: alice.clone(),
+            payload: CommandPayload::GetRecipients,
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        let broadcast = event_buf.pop_front().unwrap();
+        assert_eq!(broadcast.subscribers, vec![alice]);
+        assert!(matches!(
+            broadcast.event,
+            Event::Recipients { room: None, occupant_names } if occupant_names.is_empty()
+        ));
+    }
+
+    #[test]
+    fn room_list_puts_hub_first_and_keeps_empty_rooms() {
+        let mut state = AppState::new();
+        let alice = user("alice");
+        state.add_user_to_room(&alice, &Room::from("zoo"));
+        state.add_user_to_room(&alice, &Room::from("attic"));
+
+        assert_eq!(
+            state.list_rooms(),
+            vec![
+                ("Hub".to_string(), 0),
+                ("attic".to_string(), 1),
+                ("zoo".to_string(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn full_room_rejects_the_move_and_hub_is_never_full() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &lobby);
+        handler.insert_occupant(&bob, &Room::default());
+        handler.state.capacities.insert(lobby.clone(), 1);
+        handler.state.capacities.insert(Room::default(), 0);
+
+        let mut event_buf = VecDeque::new();
+        handler.handle_move_user(&bob, lobby.clone(), true, &mut event_buf);
+
+        assert_eq!(handler.state.get_occupied_room(&bob), Some(Room::default()));
+        assert!(matches!(
+            event_buf.pop_front().unwrap().event,
+            Event::JoinRejected { room, .. } if room == lobby
+        ));
+        assert!(!handler.state.room_is_full(&Room::default()));
+    }
+
+    #[test]
+    fn joining_a_missing_room_without_creating_it_is_rejected() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &Room::default());
+
+        let mut event_buf = VecDeque::new();
+        handler.handle_move_user(&alice, lobby.clone(), false, &mut event_buf);
+
+        assert!(matches!(
+            event_buf.pop_front().unwrap().event,
+            Event::JoinRejected { room, .. } if room == lobby
+        ));
+        assert!(!handler.state.occupancy.contains_key(&lobby));
+
+        handler.handle_move_user(&alice, lobby.clone(), true, &mut event_buf);
+        assert_eq!(handler.state.get_occupied_room(&alice), Some(lobby));
+    }
+
+    #[test]
+    fn moves_that_would_exceed_max_rooms_are_rejected() {
+        let mut handler = CommandHandler::new(AppState::new());
+        handler.state.max_rooms = Some(2);
+        let (alice, bob) = (user("alice"), user("bob"));
+        let (lobby, attic) = (Room::from("lobby"), Room::from("attic"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let mut event_buf = VecDeque::new();
+        handler.handle_move_user(&alice, lobby.clone(), true, &mut event_buf);
+
+        event_buf.clear();
+        handler.handle_move_user(&bob, attic.clone(), true, &mut event_buf);
+        assert_eq!(handler.state.get_occupied_room(&bob), Some(Room::default()));
+        assert!(matches!(
+            event_buf.pop_front().unwrap().event,
+            Event::JoinRejected { room, .. } if room == attic
+        ));
+
+        // Once lobby empties out it is collected, making room for attic.
+        handler.handle_move_user(&alice, Room::default(), true, &mut event_buf);
+        handler.handle_move_user(&bob, attic.clone(), true, &mut event_buf);
+        assert_eq!(handler.state.get_occupied_room(&bob), Some(attic));
+    }
+
+    #[test]
+    fn empty_rooms_are_collected_unless_created_explicitly() {
+        let mut handler = CommandHandler::new(AppState::new());
+        handler.state.gc_empty_rooms = true;
+        let alice = user("alice");
+        let (lobby, stage) = (Room::from("lobby"), Room::from("stage"));
+        handler.state.create_room(&stage);
+        handler.state.created_rooms.insert(stage.clone());
+        let mut event_buf = VecDeque::new();
+
+        handler.handle_move_user(&alice, lobby.clone(), true, &mut event_buf);
+        handler.handle_move_user(&alice, stage.clone(), true, &mut event_buf);
+        handler.handle_move_user(&alice, Room::default(), true, &mut event_buf);
+
+        assert!(!handler.state.occupancy.contains_key(&lobby));
+        assert!(!handler.state.chat_logs.contains_key(&lobby));
+        assert!(!handler.state.notifications.contains_key(&lobby));
+        assert!(handler.state.occupancy.contains_key(&stage));
+
+        handler.remove_occupant(&alice);
+        assert!(handler.state.occupancy.contains_key(&Room::default()));
+    }
+
+    #[test]
+    fn collected_rooms_come_back_fresh() {
+        let mut handler = CommandHandler::new(AppState::new());
+        handler.state.gc_empty_rooms = true;
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &lobby);
+        handler
+            .state
+            .record_chat_message(&alice, MessageLog::from_user(&alice, "hi".into()));
+        handler.state.capacities.insert(lobby.clone(), 3);
+
+        handler.remove_occupant(&alice);
+        assert!(!handler.state.occupancy.contains_key(&lobby));
+
+        handler.insert_occupant(&alice, &lobby);
+        assert!(handler.state.room_chat_logs(&lobby).is_empty());
+        assert!(!handler.state.capacities.contains_key(&lobby));
+    }
+
+    #[test]
+    fn empty_rooms_are_kept_when_collection_is_off() {
+        let mut handler = CommandHandler::new(AppState::new());
+        handler.state.gc_empty_rooms = false;
+        handler.state.max_rooms = None;
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &lobby);
+
+        handler.remove_occupant(&alice);
+
+        assert!(handler.state.occupancy.contains_key(&lobby));
+    }
+
+    #[test]
+    fn oversized_messages_are_rejected() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.state.max_message_bytes = DEFAULT_MAX_MESSAGE_BYTES;
+
+        for (size, accepted) in [(10 * 1024, false), (100, true)] {
+            let mut event_buf = VecDeque::new();
+            let command = Command {
+                user: alice.clone(),
+                payload: CommandPayload::RecordMessage {
+                    message: "a".repeat(size),
+                    reply_to: None,
+                },
+            };
+            handler.handle(command, &mut event_buf).unwrap();
+
+            let event = event_buf.pop_front().unwrap().event;
+            assert_eq!(matches!(event, Event::MsgReceived { .. }), accepted);
+        }
+        assert_eq!(handler.state.room_chat_logs(&Room::default()).len(), 1);
+    }
+
+    #[test]
+    fn messages_over_the_rate_limit_are_dropped() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.state.rate_limit = Some(3);
+
+        let mut delivered = vec![];
+        for i in 0..4 {
+            let mut event_buf = VecDeque::new();
+            let command = Command {
+                user: alice.clone(),
+                payload: CommandPayload::RecordMessage {
+                    message: format!("{i}"),
+                    reply_to: None,
+                },
+            };
+            handler.handle(command, &mut event_buf).unwrap();
+            delivered.push(matches!(
+                event_buf.pop_front().unwrap().event,
+                Event::MsgReceived { .. }
+            ));
+        }
+
+        assert_eq!(delivered, vec![true, true, true, false]);
+        assert_eq!(handler.state.room_chat_logs(&Room::default()).len(), 3);
+    }
+
+    fn cross_post(
+        handler: &mut CommandHandler,
+        poster: &User,
+        message_id: u64,
+        room: &Room,
+    ) -> Event {
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: poster.clone(),
+            payload: CommandPayload::CrossPost {
+                message_id,
+                target_room: room.clone(),
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+        event_buf.pop_front().unwrap().event
+    }
+
+    #[test]
+    fn cross_posts_are_refused_for_full_rooms_and_over_the_rate_limit() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &lobby);
+        let original = MessageLog::from_user(&alice, "hello".into());
+        handler.state.record_chat_message(&alice, original.clone());
+
+        handler.state.capacities.insert(lobby.clone(), 1);
+        let event = cross_post(&mut handler, &alice, original.id, &lobby);
+        assert!(matches!(
+            event,
+            Event::Notify { notice } if notice[0].contents == "lobby is full"
+        ));
+
+        handler.state.capacities.clear();
+        handler.state.rate_limit = Some(1);
+        let event = cross_post(&mut handler, &alice, original.id, &lobby);
+        assert!(matches!(event, Event::MsgReceived { .. }));
+        let event = cross_post(&mut handler, &alice, original.id, &lobby);
+        assert!(matches!(
+            event,
+            Event::Error {
+                code: ErrorCode::RateLimited,
+                ..
+            }
+        ));
+        assert_eq!(handler.state.room_chat_logs(&lobby).len(), 1);
+    }
+
+    #[test]
+    fn evicted_messages_are_announced_when_enabled() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        handler.state.history_limits.insert(Room::default(), 1);
+        handler.state.announce_evictions = true;
+        let mut sent = vec![];
+
+        let mut event_buf = VecDeque::new();
+        for text in ["first", "second"] {
+            event_buf.clear();
+            let command = Command {
+                user: alice.clone(),
+                payload: CommandPayload::RecordMessage {
+                    message: text.into(),
+                    reply_to: None,
+                },
+            };
+            handler.handle(command, &mut event_buf).unwrap();
+            if let Event::MsgReceived { msg } = &event_buf[0].event {
+                sent.push(msg.id);
+            }
+        }
+
+        assert_eq!(event_buf.len(), 2);
+        assert!(matches!(
+            event_buf[1].event,
+            Event::MsgDeleted { id } if id == sent[0]
+        ));
+        assert_eq!(event_buf[1].subscribers, vec![alice]);
+    }
+
+    fn edit(handler: &mut CommandHandler, editor: &User, message_id: u64) -> Event {
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: editor.clone(),
+            payload: CommandPayload::EditMessage {
+                message_id,
+                new_contents: "edited".into(),
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+        event_buf.pop_front().unwrap().event
+    }
+
+    #[test]
+    fn authors_can_edit_their_own_messages() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let original = MessageLog::from_user(&alice, "original".into());
+        handler.state.record_chat_message(&alice, original.clone());
+
+        let event = edit(&mut handler, &alice, original.id);
+
+        assert!(matches!(event, Event::MessageEdited { msg } if msg.id == original.id));
+        let stored = handler
+            .state
+            .find_chat_message(&Room::default(), original.id)
+            .unwrap();
+        assert_eq!(stored.contents, "edited");
+    }
+
+    #[test]
+    fn editing_someone_elses_message_is_forbidden() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let original = MessageLog::from_user(&alice, "original".into());
+        handler.state.record_chat_message(&alice, original.clone());
+
+        let event = edit(&mut handler, &bob, original.id);
+
+        assert!(matches!(
+            event,
+            Event::Error {
+                code: ErrorCode::Forbidden,
+                ..
+            }
+        ));
+        let stored = handler
+            .state
+            .find_chat_message(&Room::default(), original.id)
+            .unwrap();
+        assert_eq!(stored.contents, "original");
+    }
+
+    fn delete(handler: &mut CommandHandler, deleter: &User, message_id: u64) -> Event {
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: deleter.clone(),
+            payload: CommandPayload::DeleteMessage { message_id },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+        event_buf.pop_front().unwrap().event
+    }
+
+    #[test]
+    fn only_authors_and_admins_can_delete_messages() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        for occupant in [&alice, &bob, &admin] {
+            handler.insert_occupant(occupant, &Room::default());
+        }
+        let first = MessageLog::from_user(&alice, "first".into());
+        let second = MessageLog::from_user(&alice, "second".into());
+        handler.state.record_chat_message(&alice, first.clone());
+        handler.state.record_chat_message(&alice, second.clone());
+
+        let refused = delete(&mut handler, &bob, first.id);
+        assert!(matches!(
+            refused,
+            Event::Error {
+                code: ErrorCode::Forbidden,
+                ..
+            }
+        ));
+
+        let own = delete(&mut handler, &alice, first.id);
+        assert!(matches!(own, Event::MsgDeleted { id } if id == first.id));
+        let moderated = delete(&mut handler, &admin, second.id);
+        assert!(matches!(moderated, Event::MsgDeleted { id } if id == second.id));
+        assert!(handler.state.room_chat_logs(&Room::default()).is_empty());
+
+        let gone = delete(&mut handler, &alice, first.id);
+        assert!(matches!(gone, Event::Notify { .. }));
+    }
+
+    #[test]
+    fn admin_commands_are_refused_for_members() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::from("lobby"));
+        let mut event_buf = VecDeque::new();
+
+        let close = Command {
+            user: alice.clone(),
+            payload: CommandPayload::CloseRoom {
+                room: Room::from("lobby"),
+            },
+        };
+        handler.handle(close, &mut event_buf).unwrap();
+
+        assert!(matches!(
+            event_buf.pop_front().unwrap().event,
+            Event::Error {
+                code: ErrorCode::Forbidden,
+                ..
+            }
+        ));
+        assert!(handler.state.occupancy.contains_key(&Room::from("lobby")));
+    }
+
+    #[test]
+    fn message_ttls_past_the_maximum_are_refused() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        handler.insert_occupant(&admin, &Room::default());
+
+        for seconds in [u64::MAX, 31 * 24 * 60 * 60] {
+            let reply = send(
+                &mut handler,
+                &admin,
+                CommandPayload::SetMessageTtl {
+                    seconds: Some(seconds),
+                },
+            );
+            assert!(matches!(
+                reply[0].event,
+                Event::Error {
+                    code: ErrorCode::InvalidArgument,
+                    ..
+                }
+            ));
+        }
+        assert!(handler.state.message_ttls.is_empty());
+
+        // A ttl stretching back before the earliest representable time still sweeps.
+        handler.state.message_ttls.insert(
+            Room::default(),
+            Duration::try_seconds(i64::MAX / 1_000).unwrap(),
+        );
+        handler.sweep_expired_messages(&mut VecDeque::new());
+    }
+
+    #[test]
+    fn created_rooms_can_be_joined_and_closed() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        let alice = user("alice");
+        let stage = Room::from("stage");
+        handler.insert_occupant(&admin, &Room::default());
+        handler.insert_occupant(&alice, &Room::default());
+        let mut event_buf = VecDeque::new();
+
+        let create = Command {
+            user: admin.clone(),
+            payload: CommandPayload::CreateRoom {
+                room: stage.clone(),
+                capacity: Some(10),
+                read_only: true,
+            },
+        };
+        handler.handle(create, &mut event_buf).unwrap();
+        assert!(handler.state.room_subscribers(&stage).is_empty());
+        assert!(handler.state.chat_logs.contains_key(&stage));
+        assert!(handler.state.notifications.contains_key(&stage));
+        assert_eq!(handler.state.capacities.get(&stage), Some(&10));
+        assert!(handler.state.read_only_rooms.contains(&stage));
+
+        handler.handle_move_user(&alice, stage.clone(), false, &mut event_buf);
+        assert_eq!(handler.state.get_occupied_room(&alice), Some(stage.clone()));
+
+        event_buf.clear();
+        let close = Command {
+            user: admin,
+            payload: CommandPayload::CloseRoom {
+                room: stage.clone(),
+            },
+        };
+        handler.handle(close, &mut event_buf).unwrap();
+        assert_eq!(
+            handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+        assert!(!handler.state.occupancy.contains_key(&stage));
+        assert!(event_buf.iter().any(|broadcast| matches!(
+            &broadcast.event,
+            Event::Notify { notice } if notice[0].contents == "stage was closed"
+        ) && broadcast.subscribers.contains(&alice)));
+    }
+
+    #[test]
+    fn members_cannot_create_restricted_or_existing_rooms() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+        let mut event_buf = VecDeque::new();
+
+        for (room, capacity) in [(Room::from("stage"), Some(10)), (Room::default(), None)] {
+            let create = Command {
+                user: alice.clone(),
+                payload: CommandPayload::CreateRoom {
+                    room: room.clone(),
+                    capacity,
+                    read_only: false,
+                },
+            };
+            handler.handle(create, &mut event_buf).unwrap();
+        }
+
+        assert!(!handler.state.occupancy.contains_key(&Room::from("stage")));
+        assert!(event_buf.iter().all(|broadcast| matches!(
+            &broadcast.event,
+            Event::Notify { notice } if notice[0].severity == Severity::Error
+        )));
+    }
+
+    #[test]
+    fn closing_a_room_sends_occupants_to_the_hub() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        let alice = user("alice");
+        let lobby = Room::from("lobby");
+        handler.insert_occupant(&admin, &Room::default());
+        handler.insert_occupant(&alice, &lobby);
+        handler.state.capacities.insert(lobby.clone(), 5);
+        let mut event_buf = VecDeque::new();
+
+        let close = Command {
+            user: admin,
+            payload: CommandPayload::CloseRoom {
+                room: lobby.clone(),
+            },
+        };
+        handler.handle(close, &mut event_buf).unwrap();
+
+        assert_eq!(
+            handler.state.get_occupied_room(&alice),
+            Some(Room::default())
+        );
+        assert!(!handler.state.occupancy.contains_key(&lobby));
+        assert!(!handler.state.chat_logs.contains_key(&lobby));
+        assert!(!handler.state.capacities.contains_key(&lobby));
+    }
+
+    #[test]
+    fn admins_can_kick_users_in_other_rooms() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        let bob = user("bob");
+        handler.insert_occupant(&admin, &Room::default());
+        handler.insert_occupant(&bob, &Room::from("lobby"));
+        let mut event_buf = VecDeque::new();
+
+        let kick = Command {
+            user: admin,
+            payload: CommandPayload::KickUser {
+                target_name: "bob".into(),
+                reason: "spam".into(),
+            },
+        };
+        handler.handle(kick, &mut event_buf).unwrap();
+
+        let kicked = event_buf.pop_front().unwrap();
+        assert!(matches!(kicked.event, Event::Kicked { ref reason } if reason == "spam"));
+        assert_eq!(kicked.subscribers, vec![bob.clone()]);
+        assert_eq!(handler.state.get_occupied_room(&bob), None);
+    }
+
+    #[test]
+    fn only_admins_can_post_in_read_only_rooms() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        let alice = user("alice");
+        let news = Room::from("news");
+        handler.insert_occupant(&admin, &news);
+        handler.insert_occupant(&alice, &news);
+        handler.state.read_only_rooms.insert(news.clone());
+
+        for (sender, delivered) in [(&admin, true), (&alice, false)] {
+            let mut event_buf = VecDeque::new();
+            let command = Command {
+                user: sender.clone(),
+                payload: CommandPayload::RecordMessage {
+                    message: "news".into(),
+                    reply_to: None,
+                },
+            };
+            handler.handle(command, &mut event_buf).unwrap();
+
+            let broadcast = event_buf.pop_front().unwrap();
+            assert_eq!(
+                matches!(broadcast.event, Event::MsgReceived { .. }),
+                delivered
+            );
+            if !delivered {
+                assert_eq!(broadcast.subscribers, vec![alice.clone()]);
+            }
+        }
+        assert_eq!(handler.state.room_chat_logs(&news).len(), 1);
+    }
+
+    #[test]
+    fn only_admins_can_cross_post_into_read_only_rooms() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let mut admin = user("admin");
+        admin.is_admin = true;
+        let alice = user("alice");
+        let news = Room::from("news");
+        handler.insert_occupant(&admin, &Room::default());
+        handler.insert_occupant(&alice, &Room::default());
+        handler.state.create_room(&news);
+        handler.state.read_only_rooms.insert(news.clone());
+        let original = MessageLog::from_user(&alice, "hello".into());
+        handler.state.record_chat_message(&alice, original.clone());
+
+        let event = cross_post(&mut handler, &alice, original.id, &news);
+        assert!(matches!(
+            event,
+            Event::Error {
+                code: ErrorCode::Forbidden,
+                ..
+            }
+        ));
+        assert!(handler.state.room_chat_logs(&news).is_empty());
+
+        cross_post(&mut handler, &admin, original.id, &news);
+        assert_eq!(handler.state.room_chat_logs(&news).len(), 1);
+    }
+
+    #[test]
+    fn mentioned_occupants_are_sent_a_mention() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        handler.insert_occupant(&carol, &Room::from("lobby"));
+        let mut event_buf = VecDeque::new();
+
+        let command = Command {
+            user: alice.clone(),
+            payload: CommandPayload::RecordMessage {
+                message: "@bob @carol @nobody @alice lunch?".into(),
+                reply_to: None,
+            },
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+
+        assert!(matches!(event_buf[0].event, Event::MsgReceived { .. }));
+        assert_eq!(event_buf.len(), 2);
+        assert!(matches!(
+            &event_buf[1].event,
+            Event::Mentioned { mentioned_users, .. } if *mentioned_users == vec!["bob"]
+        ));
+        assert_eq!(event_buf[1].subscribers, vec![bob]);
+    }
+
+    #[test]
+    fn typing_updates_inside_the_interval_are_coalesced() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let notices = handler.state.room_notifications(&Room::default()).len();
+        let mut event_buf = VecDeque::new();
+
+        for is_typing in [true, true, false] {
+            let command = Command {
+                user: alice.clone(),
+                payload: CommandPayload::Typing { is_typing },
+            };
+            handler.handle(command, &mut event_buf).unwrap();
+        }
+
+        // The repeated start is dropped, the stop still clears the indicator.
+        let sent: Vec<bool> = event_buf
+            .iter()
+            .filter_map(|broadcast| match broadcast.event {
+                Event::TypingChanged { is_typing, .. } => Some(is_typing),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(event_buf.len(), 2);
+        assert_eq!(sent, vec![true, false]);
+        assert!(event_buf
+            .iter()
+            .all(|broadcast| broadcast.subscribers == vec![bob.clone()]));
+        // Typing is never written to the room history.
+        assert_eq!(
+            handler.state.room_notifications(&Room::default()).len(),
+            notices
+        );
+    }
+
+    fn send(
+        handler: &mut CommandHandler,
+        sender: &User,
+        payload: CommandPayload,
+    ) -> VecDeque<Broadcast> {
+        let mut event_buf = VecDeque::new();
+        let command = Command {
+            user: sender.clone(),
+            payload,
+        };
+        handler.handle(command, &mut event_buf).unwrap();
+        event_buf
+    }
+
+    fn page(target_name: &str) -> CommandPayload {
+        CommandPayload::Page {
+            target_name: target_name.into(),
+            message: "hi".into(),
+        }
+    }
+
+    fn paged(event_buf: &VecDeque<Broadcast>, target: &User) -> bool {
+        event_buf.iter().any(|broadcast| {
+            broadcast.subscribers.contains(target)
+                && matches!(
+                    &broadcast.event,
+                    Event::Notify { notice } if notice[0].contents.contains("paged you")
+                )
+        })
+    }
+
+    #[test]
+    fn pages_are_held_for_the_user_who_left_not_their_name() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        let impostor = User::new("impostor".into(), "bob".into(), [0; 32]);
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        send(&mut handler, &bob, CommandPayload::DropUser(0));
+
+        let reply = send(&mut handler, &alice, page("bob"));
+        assert!(matches!(
+            &reply[0].event,
+            Event::Notify { notice } if notice[0].contents.contains("will receive your page")
+        ));
+
+        let register = || CommandPayload::RegisterUser(unbounded().0, 0);
+        assert!(!paged(
+            &send(&mut handler, &impostor, register()),
+            &impostor
+        ));
+        assert!(paged(&send(&mut handler, &bob, register()), &bob));
+    }
+
+    #[test]
+    fn pages_to_unknown_users_are_refused_and_storage_is_capped() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+        handler.insert_occupant(&alice, &Room::default());
+        for leaver in [&bob, &carol] {
+            handler.insert_occupant(leaver, &Room::default());
+            send(&mut handler, leaver, CommandPayload::DropUser(0));
+        }
+        handler.state.max_stored_pages = 1;
+
+        let reply = send(&mut handler, &alice, page("nobody"));
+        assert!(matches!(
+            &reply[0].event,
+            Event::Notify { notice } if notice[0].contents == "nobody is not online"
+        ));
+        send(&mut handler, &alice, page("bob"));
+        let reply = send(&mut handler, &alice, page("carol"));
+        assert!(matches!(
+            &reply[0].event,
+            Event::Notify { notice } if notice[0].contents.starts_with("Could not hold")
+        ));
+        assert_eq!(handler.state.pages.len(), 1);
+
+        handler
+            .state
+            .expire_pages(Utc::now() + handler.state.page_ttl);
+        assert!(handler.state.pages.is_empty());
+    }
+
+    #[test]
+    fn pages_from_ignored_senders_are_dropped() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+        let ignore = |ignore| CommandPayload::Ignore {
+            target_name: "alice".into(),
+            ignore,
+        };
+        send(&mut handler, &bob, ignore(true));
+
+        assert!(!paged(&send(&mut handler, &alice, page("bob")), &bob));
+        send(&mut handler, &bob, CommandPayload::DropUser(0));
+        send(&mut handler, &alice, page("bob"));
+        assert!(handler.state.pages.is_empty());
+
+        handler.insert_occupant(&bob, &Room::default());
+        send(&mut handler, &bob, ignore(false));
+        assert!(paged(&send(&mut handler, &alice, page("bob")), &bob));
+    }
+
+    #[test]
+    fn direct_messages_to_yourself_arrive_once() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let alice = user("alice");
+        handler.insert_occupant(&alice, &Room::default());
+
+        let reply = send(
+            &mut handler,
+            &alice,
+            CommandPayload::DirectMessage {
+                target_name: "alice".into(),
+                message: "note to self".into(),
+            },
+        );
+        assert_eq!(reply.len(), 1);
+        assert!(matches!(reply[0].event, Event::DirectMsgReceived { .. }));
+        assert_eq!(reply[0].subscribers, vec![alice]);
+    }
+
+    #[test]
+    fn oversized_direct_messages_are_refused() {
+        let mut handler = CommandHandler::new(AppState::new());
+        handler.state.max_message_bytes = 8;
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+
+        let reply = send(
+            &mut handler,
+            &alice,
+            CommandPayload::DirectMessage {
+                target_name: "bob".into(),
+                message: "far too long for the limit".into(),
+            },
+        );
+        assert_eq!(reply.len(), 1);
+        assert!(matches!(
+            reply[0].event,
+            Event::Error {
+                code: ErrorCode::MessageTooLong,
+                ..
+            }
+        ));
+        assert_eq!(reply[0].subscribers, vec![alice]);
+    }
+
+    #[test]
+    fn dropped_users_keep_their_place_for_the_grace_period() {
+        let mut handler = CommandHandler::new(AppState::new());
+        handler.state.occupancy_grace = Duration::seconds(60);
+        let (alice, bob) = (user("alice"), user("bob"));
+        handler.insert_occupant(&alice, &Room::default());
+        handler.insert_occupant(&bob, &Room::default());
+
+        let only_bob = |event_buf: &VecDeque<Broadcast>| {
+            event_buf
+                .iter()
+                .all(|broadcast| broadcast.subscribers == vec![bob.clone()])
+        };
+        assert!(only_bob(&send(
+            &mut handler,
+            &bob,
+            CommandPayload::DropUser(0)
+        )));
+        assert_eq!(handler.state.get_occupied_room(&bob), Some(Room::default()));
+        let register = CommandPayload::RegisterUser(unbounded().0, 0);
+        assert!(only_bob(&send(&mut handler, &bob, register)));
+        assert!(handler.state.disconnected.is_empty());
+
+        send(&mut handler, &bob, CommandPayload::DropUser(0));
+        handler
+            .state
+            .disconnected
+            .insert(bob.clone(), Utc::now() - Duration::seconds(61));
+        let mut event_buf = VecDeque::new();
+        handler.sweep_expired_messages(&mut event_buf);
+        assert_eq!(handler.state.get_occupied_room(&bob), None);
+        assert!(event_buf.iter().any(|broadcast| {
+            broadcast.subscribers == vec![alice.clone()]
+                && matches!(&broadcast.event, Event::UserLeft { user, .. } if *user == bob)
+        }));
+    }
+
+    #[test]
+    fn full_rooms_admit_their_waitlist_in_order() {
+        let mut handler = CommandHandler::new(AppState::new());
+        let lobby = Room::from("lobby");
+        let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+        handler.insert_occupant(&alice, &lobby);
+        handler.insert_occupant(&bob, &Room::default());
+        handler.insert_occupant(&carol, &Room::default());
+        handler.state.capacities.insert(lobby.clone(), 1);
+
+        let wait = || CommandPayload::JoinOrWait {
+            room: lobby.clone(),
+        };
+        let position = |event_buf: VecDeque<Broadcast>| match &event_buf[0].event {
+            Event::Waitlisted { position, .. } => *position,
+            _ => panic!("expected Waitlisted"),
+        };
+        assert_eq!(position(send(&mut handler, &bob, wait())), 1);
+        assert_eq!(position(send(&mut handler, &carol, wait())), 2);
+        assert_eq!(position(send(&mut handler, &bob, wait())), 1);
+
+        let to_hub = CommandPayload::MoveUser {
+            target_room: Room::default(),
+            create_if_missing: false,
+        };
+        let event_buf = send(&mut handler, &alice, to_hub);
+        assert!(event_buf.iter().any(|broadcast| {
+            broadcast.subscribers == vec![bob.clone()]
+                && matches!(&broadcast.event, Event::UserJoined { room, .. } if *room == lobby)
+        }));
+        assert_eq!(handler.state.get_occupied_room(&bob), Some(lobby.clone()));
+        assert_eq!(handler.state.waitlists[&lobby], vec![carol.clone()]);
+
+        send(&mut handler, &carol, CommandPayload::DropUser(0));
+        assert!(handler.state.waitlists.is_empty());
+    }
+
+    #[test]
+    fn rooms_keep_their_own_history_limit() {
+        let mut state = AppState::new();
+        let (alice, bob) = (user("alice"), user("bob"));
+        let lobby = Room::from("lobby");
+        state.add_user_to_room(&alice, &Room::default());
+        state.add_user_to_room(&bob, &lobby);
+        state.history_limits.insert(lobby.clone(), 5);
+
+        for i in 0..30 {
+            state.record_chat_message(&alice, MessageLog::from_user(&alice, format!("{i}")));
+            state.record_chat_message(&bob, MessageLog::from_user(&bob, format!("{i}")));
+        }
+
+        assert_eq!(state.room_chat_logs(&lobby).len(), 5);
+        assert_eq!(state.room_chat_logs(&Room::default()).len(), state.max_logs);
+    }
 }
@@ -1,30 +1,43 @@
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use futures_util::StreamExt;
+use futures_channel::mpsc::{Receiver, Sender};
+use futures_util::{SinkExt, StreamExt};
 
 use anyhow::{anyhow, Result};
 
 use crate::domain::commands::Command;
+use crate::services::login::getenv;
+
+/// Commands each leg of the command path (sessions to gateway, gateway to App)
+/// buffers before senders have to wait. Large enough to absorb a burst from every
+/// session at once, small enough that a stalled App is felt by the sessions long
+/// before it shows up in memory. Override with MARAIN_COMMAND_CHANNEL_CAPACITY.
+pub const DEFAULT_COMMAND_CHANNEL_CAPACITY: usize = 1024;
+
+pub fn command_channel_capacity() -> usize {
+    match getenv("MARAIN_COMMAND_CHANNEL_CAPACITY").parse::<usize>() {
+        Ok(capacity) if capacity > 0 => capacity,
+        _ => DEFAULT_COMMAND_CHANNEL_CAPACITY,
+    }
+}
 
 pub struct AppGateway {
-    command_handler_sink: UnboundedSender<Command>,
-    session_worker_source: UnboundedReceiver<Command>,
+    command_handler_sink: Sender<Command>,
+    session_worker_source: Receiver<Command>,
 }
 
 impl AppGateway {
-    pub fn init(
-        app_sink: UnboundedSender<Command>,
-        sessions_source: UnboundedReceiver<Command>,
-    ) -> Self {
+    pub fn init(app_sink: Sender<Command>, sessions_source: Receiver<Command>) -> Self {
         Self {
             command_handler_sink: app_sink,
             session_worker_source: sessions_source,
         }
     }
 
+    /// Waits for room in the App's channel before taking the next command, so a
+    /// backed up App stops the gateway and in turn the session workers.
     async fn session_worker_fan_in(&mut self) -> Result<()> {
         loop {
             if let Some(s) = self.session_worker_source.next().await {
-                self.command_handler_sink.unbounded_send(s).unwrap()
+                self.command_handler_sink.send(s).await?
             } else {
                 return Err(anyhow!(
                     "App gateway worker stopped due to upstream channel closure"
@@ -42,3 +55,40 @@ impl AppGateway {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{commands::CommandPayload, user::User};
+    use futures_channel::mpsc::channel;
+
+    fn ping() -> Command {
+        Command {
+            user: User::new("alice".into(), "alice".into(), [0; 32]),
+            payload: CommandPayload::Ping,
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_app_pushes_back_on_sessions() {
+        let (app_sink, mut app_source) = channel(1);
+        let (mut session_sink, gateway_source) = channel(1);
+        AppGateway::init(app_sink, gateway_source).run();
+
+        // With nothing reading the App's end, both legs fill and then refuse more.
+        let mut accepted = 0;
+        while session_sink.try_send(ping()).is_ok() {
+            accepted += 1;
+            tokio::task::yield_now().await;
+            assert!(accepted < 100, "commands were never refused");
+        }
+        assert!(session_sink.try_send(ping()).unwrap_err().is_full());
+
+        // Once the App catches up the sessions can send again.
+        for _ in 0..accepted {
+            app_source.next().await.unwrap();
+        }
+        tokio::task::yield_now().await;
+        assert!(session_sink.try_send(ping()).is_ok());
+    }
+}
@@ -1,28 +1,55 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use chrono::Utc;
-use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_channel::mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender};
 use futures_util::stream::SplitStream;
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
 use marain_api::prelude::{ClientMsg, ClientMsgBody, Timestamp};
+use rand_core::{OsRng, RngCore};
 use sphinx::prelude::cbc_decode;
-use tokio::net::TcpStream;
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio::time::{interval_at, Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::{
+    tungstenite::{Error as WsError, Message},
+    WebSocketStream,
+};
 
 use crate::domain::commands::{Command, CommandPayload};
-use crate::domain::events::Event;
+use crate::domain::events::{ErrorCode, Event};
+use crate::domain::notification_log::{NotificationLog, Severity};
 use crate::domain::room::Room;
 use crate::domain::user::User;
-use crate::services::message_builder::SocketSendAdaptor;
+use crate::error::MarainServerError;
+use crate::services::command_parser::{self, ParsedCommand};
+use crate::services::decode::decode_client_msg;
+use crate::services::login::getenv;
+use crate::services::message_builder::{DisconnectReason, SocketSendAdaptor};
+use crate::services::transport::{ClientStream, ConnectionPermit};
+use crate::services::{metrics, resume};
 
 use anyhow::{anyhow, Result};
 
+const DEFAULT_NOTICE_PAGE: usize = 10;
+const DEFAULT_ROOM_SEARCH_LIMIT: usize = 10;
+const TIME_REQUEST_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+/// Time requests dropped within one burst before the client is logged as misbehaving.
+const TIME_REQUEST_WARN_AFTER: u32 = 50;
+const END_SESSION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+const DISCONNECT_SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(1);
+const DEFAULT_PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Consecutive heartbeat pings without a pong before the connection is treated as dead.
+const MAX_UNANSWERED_PINGS: u32 = 2;
+
+/// Source of session generations, unique for the life of the process.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
 struct SessionBus {
-    app_gateway_sink: UnboundedSender<Command>,
+    app_gateway_sink: Sender<Command>,
     event_sink: Option<UnboundedSender<Event>>,
     event_source: UnboundedReceiver<Event>,
 }
 
 impl SessionBus {
-    fn new(gateway_sink: UnboundedSender<Command>) -> Self {
+    fn new(gateway_sink: Sender<Command>) -> Self {
         let (sink, src) = unbounded();
         Self {
             app_gateway_sink: gateway_sink,
@@ -35,25 +62,64 @@ impl SessionBus {
         self.event_source.next().await
     }
 
-    fn send_command(&mut self, command: Command) {
-        self.app_gateway_sink.unbounded_send(command).unwrap()
+    /// Waits while the command path is full, so a busy App slows this session down.
+    async fn send_command(&mut self, command: Command) {
+        self.app_gateway_sink.send(command).await.unwrap()
+    }
+
+    /// Asks the App to drop the user and waits for their UserLeft. Returns false if
+    /// it does not arrive within the timeout or the App has stopped sending events.
+    async fn drop_user(
+        &mut self,
+        user: &User,
+        generation: u64,
+        timeout: std::time::Duration,
+    ) -> bool {
+        let left = async {
+            self.send_command(Command {
+                user: user.clone(),
+                payload: CommandPayload::DropUser(generation),
+            })
+            .await;
+            while let Some(event) = self.next_event().await {
+                if matches!(event, Event::UserLeft { user: ref left, .. } if left == user) {
+                    return true;
+                }
+            }
+            false
+        };
+        tokio::time::timeout(timeout, left).await.unwrap_or(false)
     }
 }
 
 pub struct SessionWorker {
     user: User,
     app_socket: SessionBus,
-    user_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
-    user_source: SplitStream<WebSocketStream<TcpStream>>,
+    user_sink: SplitSink<WebSocketStream<ClientStream>, Message>,
+    user_source: SplitStream<WebSocketStream<ClientStream>>,
     shared_secret: [u8; 32],
+    key_fingerprint: String,
+    last_time_request: Option<Instant>,
+    dropped_time_requests: u32,
+    heartbeat: Interval,
+    unanswered_pings: u32,
+    idle_timeout: Option<std::time::Duration>,
+    last_client_msg: Instant,
+    connection: Option<ConnectionPermit>,
+    /// Set once a resumed login has taken over this session's user.
+    taken_over: bool,
+    /// Tags this connection's RegisterUser and DropUser, so the App can ignore a
+    /// drop from this session once another has resumed its user.
+    generation: u64,
 }
 
 impl SessionWorker {
     pub fn new(
         user: User,
-        gateway_sink: UnboundedSender<Command>,
-        user_sink: SplitSink<WebSocketStream<TcpStream>, Message>,
-        user_source: SplitStream<WebSocketStream<TcpStream>>,
+        gateway_sink: Sender<Command>,
+        user_sink: SplitSink<WebSocketStream<ClientStream>, Message>,
+        user_source: SplitStream<WebSocketStream<ClientStream>>,
+        key_fingerprint: String,
     ) -> Self {
         SessionWorker {
             user: user.clone(),
@@ -61,6 +127,68 @@ impl SessionWorker {
             user_sink,
             user_source,
             shared_secret: user.shared_secret.clone(),
+            key_fingerprint,
+            last_time_request: None,
+            dropped_time_requests: 0,
+            heartbeat: SessionWorker::heartbeat(),
+            unanswered_pings: 0,
+            idle_timeout: match getenv("MARAIN_IDLE_TIMEOUT_SECS").parse::<u64>() {
+                Ok(secs) if secs > 0 => Some(std::time::Duration::from_secs(secs)),
+                _ => None,
+            },
+            last_client_msg: Instant::now(),
+            connection: None,
+            taken_over: false,
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Keeps the session's place under the connection limit until end_session.
+    pub fn hold_connection(&mut self, permit: ConnectionPermit) {
+        self.connection = Some(permit);
+    }
+
+    /// Resolves once the client has sent nothing for the idle timeout, or never if
+    /// MARAIN_IDLE_TIMEOUT_SECS is unset.
+    async fn idle(timeout: Option<std::time::Duration>, last_client_msg: Instant) {
+        match timeout {
+            Some(timeout) => tokio::time::sleep_until(last_client_msg + timeout).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Pings go out every MARAIN_PING_INTERVAL seconds, or every 30 seconds if it
+    /// is unset or invalid, so that idle connections are not dropped by proxies.
+    fn heartbeat() -> Interval {
+        let period = match getenv("MARAIN_PING_INTERVAL").parse::<u64>() {
+            Ok(secs) if secs > 0 => std::time::Duration::from_secs(secs),
+            _ => DEFAULT_PING_INTERVAL,
+        };
+        let mut heartbeat = interval_at(Instant::now() + period, period);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        heartbeat
+    }
+
+    /// Sends the next heartbeat ping. Returns false once the client has left too
+    /// many pings unanswered or the ping cannot be sent.
+    async fn send_heartbeat(&mut self) -> bool {
+        if self.unanswered_pings >= MAX_UNANSWERED_PINGS {
+            log::warn!(
+                "{} left {} pings unanswered, ending session",
+                self.user.name,
+                self.unanswered_pings
+            );
+            return false;
+        }
+        match self.user_sink.send(Message::Ping(vec![])).await {
+            Ok(_) => {
+                self.unanswered_pings += 1;
+                true
+            }
+            Err(e) => {
+                log::warn!("Could not send heartbeat ping, ending session. Error: {e}");
+                false
+            }
         }
     }
 
@@ -75,18 +203,18 @@ impl SessionWorker {
         }
     }
 
-    fn decrypt(user_key: &[u8; 32], enc: Vec<u8>) -> Result<Vec<u8>> {
+    fn decrypt(user_key: &[u8; 32], enc: Vec<u8>) -> Result<Vec<u8>, MarainServerError> {
         match cbc_decode(user_key.to_vec(), enc) {
             Ok(dec) => Ok(dec),
             Err(e) => {
                 log::error!("Failed to decode user message with error: {e}");
-                Err(anyhow!("{e:?}"))
+                Err(MarainServerError::Decryption(format!("{e:?}")))
             }
         }
     }
 
     fn deserialize(msg: Vec<u8>) -> Result<ClientMsg, Box<bincode::ErrorKind>> {
-        bincode::deserialize::<ClientMsg>(&msg[..])
+        decode_client_msg(&msg[..])
     }
 
     fn parse_client_msg(&mut self, msg: ClientMsg) -> Result<Command> {
@@ -94,12 +222,15 @@ impl SessionWorker {
             ClientMsg { body, .. } => match body {
                 ClientMsgBody::SendToRoom { contents: message } => Ok(Command {
                     user: self.user.clone(),
-                    payload: CommandPayload::RecordMessage { message },
+                    payload: self.parse_room_text(message),
                 }),
+                // Move has no room for a flag, so it always creates. /join is the
+                // way to move only into rooms that already exist.
                 ClientMsgBody::Move { target } => Ok(Command {
                     user: self.user.clone(),
                     payload: CommandPayload::MoveUser {
                         target_room: Room { name: target },
+                        create_if_missing: true,
                     },
                 }),
                 ClientMsgBody::GetTime => Ok(Command {
@@ -113,23 +244,234 @@ impl SessionWorker {
         }
     }
 
+    /// Text sent to a room may carry a slash command rather than a chat message.
+    fn parse_room_text(&self, message: String) -> CommandPayload {
+        match command_parser::parse(&message) {
+            Some(command) => self.route_parsed_command(command),
+            None => CommandPayload::RecordMessage {
+                message,
+                reply_to: None,
+            },
+        }
+    }
+
+    fn route_parsed_command(&self, command: ParsedCommand) -> CommandPayload {
+        match command {
+            ParsedCommand::Me { action } => CommandPayload::RecordMessage {
+                message: format!("* {} {action}", self.user.name),
+                reply_to: None,
+            },
+            ParsedCommand::Help => CommandPayload::Help,
+            ParsedCommand::Swap => CommandPayload::SwapRoom,
+            ParsedCommand::Ping => CommandPayload::Ping,
+            ParsedCommand::KeepAlive => CommandPayload::KeepAlive,
+            ParsedCommand::Fingerprint => CommandPayload::GetKeyFingerprint,
+            ParsedCommand::Limits => CommandPayload::GetLimits,
+            ParsedCommand::Who => CommandPayload::GetRecipients,
+            ParsedCommand::Refresh => CommandPayload::GetRoomData,
+            ParsedCommand::List => CommandPayload::ListRooms,
+            ParsedCommand::Roll { sides } => CommandPayload::RecordMessage {
+                message: format!(
+                    "* {} rolled {} (1-{sides})",
+                    self.user.name,
+                    OsRng.next_u32() % sides + 1
+                ),
+                reply_to: None,
+            },
+            ParsedCommand::Page {
+                target_name,
+                message,
+            } => CommandPayload::Page {
+                target_name,
+                message,
+            },
+            ParsedCommand::DirectMessage {
+                target_name,
+                message,
+            } => CommandPayload::DirectMessage {
+                target_name,
+                message,
+            },
+            ParsedCommand::Notices { limit } => CommandPayload::FetchNotifications {
+                limit: limit.unwrap_or(DEFAULT_NOTICE_PAGE),
+            },
+            ParsedCommand::GlobalNotices { limit } => CommandPayload::GlobalNotifications {
+                limit: limit.unwrap_or(DEFAULT_NOTICE_PAGE),
+            },
+            ParsedCommand::RoomInfo { room } => CommandPayload::RoomInfo {
+                room: Room::from(room.as_str()),
+            },
+            ParsedCommand::SearchRooms { prefix, limit } => CommandPayload::SearchRooms {
+                prefix,
+                limit: limit.unwrap_or(DEFAULT_ROOM_SEARCH_LIMIT),
+            },
+            ParsedCommand::Roster { page } => CommandPayload::RoomRoster {
+                page: page.unwrap_or(1),
+            },
+            ParsedCommand::CrossPost {
+                message_id,
+                target_room,
+            } => CommandPayload::CrossPost {
+                message_id,
+                target_room: Room::from(target_room.as_str()),
+            },
+            ParsedCommand::Reply {
+                message_id,
+                message,
+            } => CommandPayload::RecordMessage {
+                message,
+                reply_to: Some(message_id),
+            },
+            ParsedCommand::MessageTtl { seconds } => CommandPayload::SetMessageTtl { seconds },
+            ParsedCommand::Capacity { capacity } => CommandPayload::SetCapacity { capacity },
+            ParsedCommand::History { limit } => CommandPayload::SetHistoryLimit { limit },
+            ParsedCommand::UserRooms { target_name } => CommandPayload::UserRooms { target_name },
+            ParsedCommand::GetMessage { message_id } => {
+                CommandPayload::GetMessage { id: message_id }
+            }
+            ParsedCommand::MoveAll { from, to } => CommandPayload::MoveAll {
+                from: Room::from(from.as_str()),
+                to: Room::from(to.as_str()),
+            },
+            ParsedCommand::Nick { new_name } => CommandPayload::Rename { new_name },
+            ParsedCommand::Typing { is_typing } => CommandPayload::Typing { is_typing },
+            ParsedCommand::Ignore {
+                target_name,
+                ignore,
+            } => CommandPayload::Ignore {
+                target_name,
+                ignore,
+            },
+            ParsedCommand::ReadOnly { read_only } => CommandPayload::SetReadOnly { read_only },
+            ParsedCommand::Kick {
+                target_name,
+                reason,
+            } => CommandPayload::KickUser {
+                target_name,
+                reason,
+            },
+            ParsedCommand::Join { room } => CommandPayload::MoveUser {
+                target_room: Room::from(room.as_str()),
+                create_if_missing: false,
+            },
+            ParsedCommand::Wait { room } => CommandPayload::JoinOrWait {
+                room: Room::from(room.as_str()),
+            },
+            ParsedCommand::Create {
+                room,
+                capacity,
+                read_only,
+            } => CommandPayload::CreateRoom {
+                room: Room::from(room.as_str()),
+                capacity,
+                read_only,
+            },
+            ParsedCommand::Close { room } => CommandPayload::CloseRoom {
+                room: Room::from(room.as_str()),
+            },
+            ParsedCommand::Delete { message_id } => CommandPayload::DeleteMessage { message_id },
+            ParsedCommand::Edit {
+                message_id,
+                new_contents,
+            } => CommandPayload::EditMessage {
+                message_id,
+                new_contents,
+            },
+        }
+    }
+
     async fn handle_client_msg(&mut self, msg: ClientMsg) -> Result<()> {
         match self.parse_client_msg(msg) {
             Ok(cmd) => match cmd.payload {
-                CommandPayload::Time(t) => {
-                    let ts = SocketSendAdaptor::prepare_send_time(&self.shared_secret, t)?;
-                    self.user_sink.send(ts).await?;
+                CommandPayload::Time(t) if self.allow_time_request() => self.send_time(t).await,
+                CommandPayload::Time(_) => Ok(()),
+                // The session already knows the key it logged in with, no need to ask the App.
+                CommandPayload::GetKeyFingerprint => {
+                    let msg = SocketSendAdaptor::key_fingerprint_response(
+                        &self.shared_secret,
+                        &self.key_fingerprint,
+                    )?;
+                    self.user_sink.send(msg).await?;
+                    Ok(())
+                }
+                // The idle timer was reset when the message arrived, that is all it is for.
+                CommandPayload::KeepAlive => Ok(()),
+                CommandPayload::Help => {
+                    let help = NotificationLog::new(command_parser::HELP_TEXT.into());
+                    let msg = SocketSendAdaptor::notification_response(&self.shared_secret, help)?;
+                    self.user_sink.send(msg).await?;
                     Ok(())
                 }
                 _ => {
-                    self.app_socket.send_command(cmd);
+                    self.app_socket.send_command(cmd).await;
                     Ok(())
                 }
             },
-            Err(e) => return Err(anyhow!("Error in handle_command: {e:?}")),
+            // The session carries on, the client is only told the message was not understood.
+            Err(e) => {
+                log::warn!("Unhandled client message from {}: {e}", self.user.name);
+                let msg = SocketSendAdaptor::prepare_send_error(
+                    &self.shared_secret,
+                    ErrorCode::UnknownCommand,
+                    "The server does not handle that message",
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Allows at most one time request per TIME_REQUEST_INTERVAL, excess requests
+    /// are dropped since the next allowed reply supersedes them.
+    fn allow_time_request(&mut self) -> bool {
+        let now = Instant::now();
+        match self.last_time_request {
+            Some(last) if now.duration_since(last) < TIME_REQUEST_INTERVAL => {
+                self.dropped_time_requests += 1;
+                if self.dropped_time_requests == TIME_REQUEST_WARN_AFTER {
+                    log::warn!(
+                        "{} has sent {} time requests too quickly",
+                        self.user.name,
+                        self.dropped_time_requests
+                    );
+                }
+                false
+            }
+            _ => {
+                self.last_time_request = Some(now);
+                self.dropped_time_requests = 0;
+                true
+            }
+        }
+    }
+
+    /// Replies to a time request. A failed reply is logged and skipped, only a
+    /// dead connection is reported as an error.
+    async fn send_time(&mut self, t: Timestamp) -> Result<()> {
+        let ts = match SocketSendAdaptor::prepare_send_time(&self.shared_secret, t) {
+            Ok(ts) => ts,
+            Err(e) => {
+                log::warn!("Could not prepare time response, skipping it. Error: {e}");
+                return Ok(());
+            }
+        };
+        match self.user_sink.send(ts).await {
+            Err(e) if SessionWorker::is_transport_dead(&e) => Err(e.into()),
+            Err(e) => {
+                log::warn!("Could not send time response, skipping it. Error: {e}");
+                Ok(())
+            }
+            Ok(_) => Ok(()),
         }
     }
 
+    fn is_transport_dead(e: &WsError) -> bool {
+        matches!(
+            e,
+            WsError::ConnectionClosed | WsError::AlreadyClosed | WsError::Io(_)
+        )
+    }
+
     async fn handle_event(&mut self, event: Event) -> Result<()> {
         match event {
             Event::UserRegistered { token } => {
@@ -137,8 +479,7 @@ impl SessionWorker {
                 Ok(())
             }
             Event::MsgReceived { msg } => {
-                let msg =
-                    SocketSendAdaptor::prepare_send_msg_log(msg, &self.user, &self.shared_secret)?;
+                let msg = SocketSendAdaptor::prepare_send_msg_log(msg, &self.shared_secret)?;
                 self.user_sink.send(msg).await?;
                 Ok(())
             }
@@ -160,6 +501,22 @@ impl SessionWorker {
 
                 Ok(())
             }
+            Event::RoomData {
+                room,
+                msg_log,
+                notifications,
+                occupant_names,
+            } => {
+                let msg = SocketSendAdaptor::room_data_response(
+                    &self.shared_secret,
+                    msg_log,
+                    notifications,
+                    occupant_names,
+                    &room,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
             Event::UserJoined {
                 msg_log,
                 notifications,
@@ -180,23 +537,243 @@ impl SessionWorker {
                 // self.user_sink.send(msg).await?;
                 Ok(())
             }
+            Event::OccupancyChanged {
+                room,
+                occupant_names,
+            } => {
+                let msg = SocketSendAdaptor::recipients_response(
+                    &self.shared_secret,
+                    Some(&room),
+                    occupant_names,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::JoinRejected { room, reason } => {
+                let msg =
+                    SocketSendAdaptor::join_rejected_response(&self.shared_secret, &room, &reason)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            // The protocol has no waitlist message, so the client is told in a notice.
+            Event::Waitlisted { room, position } => {
+                let notice = NotificationLog::new(format!(
+                    "{} is full, you are number {position} in the queue",
+                    room.name
+                ));
+                let msg = SocketSendAdaptor::notification_response(&self.shared_secret, notice)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Notify { notice }
+            | Event::NotificationPage {
+                notifications: notice,
+            }
+            | Event::GlobalNotifications {
+                notifications: notice,
+            } => {
+                for n in notice {
+                    let msg = SocketSendAdaptor::notification_response(&self.shared_secret, n)?;
+                    self.user_sink.send(msg).await?;
+                }
+                Ok(())
+            }
+            Event::UserRooms { name, rooms } => {
+                let msg =
+                    SocketSendAdaptor::user_rooms_response(&self.shared_secret, &name, rooms)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Renamed { new, .. } => {
+                self.user = new;
+                Ok(())
+            }
+            Event::TypingChanged {
+                user, is_typing, ..
+            } => {
+                let msg =
+                    SocketSendAdaptor::typing_response(&self.shared_secret, &user, is_typing)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Mentioned { msg, .. } => {
+                let msg = SocketSendAdaptor::mentioned_response(&self.shared_secret, &msg)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::MessageEdited { msg } => {
+                let msg = SocketSendAdaptor::msg_edited_response(msg, &self.shared_secret)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::DirectMsgReceived { msg } => {
+                let msg = SocketSendAdaptor::direct_msg_response(msg, &self.shared_secret)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::MsgDeleted { id } => {
+                let msg = SocketSendAdaptor::msg_deleted_response(&self.shared_secret, id)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::SingleMessage { message } => {
+                let msg = SocketSendAdaptor::single_message_response(&self.shared_secret, message)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Recipients {
+                room,
+                occupant_names,
+            } => {
+                let msg = SocketSendAdaptor::recipients_response(
+                    &self.shared_secret,
+                    room.as_ref(),
+                    occupant_names,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::RoomList { rooms } | Event::RoomSearchResults { rooms } => {
+                let msg = SocketSendAdaptor::room_search_response(&self.shared_secret, rooms)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::RoomInfo {
+                room,
+                exists,
+                occupant_count,
+            } => {
+                let msg = SocketSendAdaptor::prepare_send_room_info(
+                    &self.shared_secret,
+                    &room,
+                    exists,
+                    occupant_count,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Roster {
+                room,
+                members,
+                page,
+                pages,
+            } => {
+                let msg = SocketSendAdaptor::roster_response(
+                    &self.shared_secret,
+                    &room,
+                    members,
+                    page,
+                    pages,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Limits {
+                max_rooms,
+                max_message_bytes,
+                history_window,
+                features,
+            } => {
+                let msg = SocketSendAdaptor::limits_response(
+                    &self.shared_secret,
+                    max_rooms,
+                    max_message_bytes,
+                    history_window,
+                    features,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Pong {
+                server_version,
+                uptime_secs,
+                active_connections,
+            } => {
+                let msg = SocketSendAdaptor::pong_response(
+                    &self.shared_secret,
+                    &server_version,
+                    uptime_secs,
+                    active_connections,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::ServerStats {
+                connections,
+                rooms,
+                messages_per_min,
+            } => {
+                let msg = SocketSendAdaptor::prepare_send_server_stats(
+                    &self.shared_secret,
+                    connections,
+                    rooms,
+                    messages_per_min,
+                )?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            Event::Error { code, message } => {
+                let msg =
+                    SocketSendAdaptor::prepare_send_error(&self.shared_secret, code, &message)?;
+                self.user_sink.send(msg).await?;
+                Ok(())
+            }
+            // These end the session, so they are dealt with in run before reaching here.
+            Event::ServerShutdown | Event::Kicked { .. } => Ok(()),
         }
     }
 
     pub async fn end_session(&mut self) {
-        self.app_socket.send_command(Command {
-            user: self.user.clone(),
-            payload: CommandPayload::DropUser,
-        });
-        loop {
-            match self.app_socket.next_event().await {
-                Some(Event::UserLeft { user, .. }) if user == self.user => {
-                    return;
-                }
-                _ => {
-                    continue;
-                }
-            };
+        // Once taken over the user belongs to the session that resumed them.
+        if !self.taken_over {
+            if !self
+                .app_socket
+                .drop_user(&self.user, self.generation, END_SESSION_TIMEOUT)
+                .await
+            {
+                log::warn!(
+                    "No UserLeft for {:?} within {END_SESSION_TIMEOUT:?}, ending session anyway",
+                    self.user
+                );
+            }
+            resume::release(&self.user);
+        }
+        metrics::ACTIVE_SESSIONS.decrement();
+        self.connection.take();
+    }
+
+    /// Best effort notice to the client before a fatal close. Failures are only
+    /// logged and the send is bounded so a stuck socket cannot delay shutdown.
+    async fn send_disconnect_reason(&mut self, reason: DisconnectReason) {
+        let msg = match SocketSendAdaptor::disconnect_response(&self.shared_secret, reason) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("Could not build disconnect reason {reason:?}. Error: {e}");
+                return;
+            }
+        };
+        match tokio::time::timeout(DISCONNECT_SEND_TIMEOUT, self.user_sink.send(msg)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::debug!("Could not send disconnect reason {reason:?}. Error: {e}"),
+            Err(_) => log::debug!("Timed out sending disconnect reason {reason:?}"),
+        }
+    }
+
+    /// Best effort, like send_disconnect_reason, since the session is ending anyway.
+    async fn send_kick_reason(&mut self, reason: String) {
+        let text = match reason.as_str() {
+            "" => "You were kicked".to_string(),
+            reason => format!("You were kicked: {reason}"),
+        };
+        let notice = NotificationLog::new(text).with_severity(Severity::Error);
+        let Ok(msg) = SocketSendAdaptor::notification_response(&self.shared_secret, notice) else {
+            return;
+        };
+        if tokio::time::timeout(DISCONNECT_SEND_TIMEOUT, self.user_sink.send(msg))
+            .await
+            .is_err()
+        {
+            log::debug!("Timed out telling {} why they were kicked", self.user.name);
         }
     }
 
@@ -204,10 +781,12 @@ impl SessionWorker {
         let event_sink = self.give_sink()?;
         let register = Command {
             user: self.user.clone(),
-            payload: CommandPayload::RegisterUser(event_sink),
+            payload: CommandPayload::RegisterUser(event_sink, self.generation),
         };
 
-        self.app_socket.send_command(register);
+        // Commands from one session reach the App in the order they were sent, so
+        // anything the client sends from here on is handled after registration.
+        self.app_socket.send_command(register).await;
 
         'main_loop: loop {
             tokio::select! {
@@ -216,11 +795,18 @@ impl SessionWorker {
                         Ok(Message::Binary(data)) => data,
                         Err(e) => {
                             log::error!("Invalid protocol, ending session. Error: {e}");
+                            self.send_disconnect_reason(DisconnectReason::InvalidProtocol).await;
                             break 'main_loop;
                         },
                         Ok(Message::Close {..}) => {
                             break 'main_loop;
                         },
+                        Ok(Message::Pong(_)) => {
+                            self.unanswered_pings = 0;
+                            continue;
+                        },
+                        // tungstenite queues the reply to a client ping by itself.
+                        Ok(Message::Ping(_)) => continue,
                         _ => {
                             log::warn!("Unhandled message: {msg:?}");
                             continue;
@@ -231,6 +817,7 @@ impl SessionWorker {
                         Ok(data) => data,
                         Err(e) => {
                             log::error!("Decryption error, ending session. Error: {e}");
+                            self.send_disconnect_reason(DisconnectReason::DecryptionFailure).await;
                             break 'main_loop;
                         }
                     };
@@ -242,21 +829,54 @@ impl SessionWorker {
                             continue;
                         }
                     };
+                    self.last_client_msg = Instant::now();
 
                     match self.handle_client_msg(deserialized).await {
                         Err(e) => {
                             log::error!("Failed to push user message downstream, exiting user session. Error: {e}");
+                            self.send_disconnect_reason(DisconnectReason::Internal).await;
                             break 'main_loop;
                         },
                         _ => {}
                     };
                 }
 
-                Some(event) = self.app_socket.next_event() => {
+                _ = SessionWorker::idle(self.idle_timeout, self.last_client_msg) => {
+                    log::info!("{} has been idle too long, ending session", self.user.name);
+                    self.send_disconnect_reason(DisconnectReason::IdleTimeout).await;
+                    break 'main_loop;
+                }
+
+                _ = self.heartbeat.tick() => {
+                    if !self.send_heartbeat().await {
+                        break 'main_loop;
+                    }
+                }
+
+                event = self.app_socket.next_event() => {
+                    // The App only closes a live session's channel when a resumed
+                    // login subscribes in its place.
+                    let Some(event) = event else {
+                        log::info!("{} resumed on another connection, ending this session", self.user.name);
+                        self.taken_over = true;
+                        self.send_disconnect_reason(DisconnectReason::ResumedElsewhere).await;
+                        break 'main_loop;
+                    };
+                    if let Event::ServerShutdown = event {
+                        self.send_disconnect_reason(DisconnectReason::ServerShutdown).await;
+                        break 'main_loop;
+                    }
+                    if let Event::Kicked { reason } = event {
+                        resume::revoke(&self.user);
+                        self.send_kick_reason(reason).await;
+                        self.send_disconnect_reason(DisconnectReason::Kicked).await;
+                        break 'main_loop;
+                    }
                     match self.handle_event(event).await {
                         Ok(_) => {},
                         Err(e) => {
                             log::warn!("Error in SessionWorker event handler. Error: {e:?}");
+                            self.send_disconnect_reason(DisconnectReason::Internal).await;
                             break 'main_loop;
                         }
                     }
@@ -267,3 +887,68 @@ impl SessionWorker {
         return Ok(());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workers::app::App;
+    use futures_channel::mpsc::channel;
+
+    fn user(name: &str) -> User {
+        User::new(name.into(), name.into(), [0; 32])
+    }
+
+    async fn register(bus: &mut SessionBus, user: &User) {
+        let sink = bus.event_sink.take().unwrap();
+        bus.send_command(Command {
+            user: user.clone(),
+            payload: CommandPayload::RegisterUser(sink, 0),
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn dropped_user_leaves_their_room() {
+        let (app_sink, gateway_source) = channel(16);
+        App::init(gateway_source).run();
+        let (alice, bob) = (user("alice"), user("bob"));
+        let mut alice_bus = SessionBus::new(app_sink.clone());
+        let mut bob_bus = SessionBus::new(app_sink);
+        register(&mut alice_bus, &alice).await;
+        register(&mut bob_bus, &bob).await;
+
+        assert!(
+            alice_bus
+                .drop_user(&alice, 0, std::time::Duration::from_secs(1))
+                .await
+        );
+
+        bob_bus
+            .send_command(Command {
+                user: bob.clone(),
+                payload: CommandPayload::RoomInfo {
+                    room: Room::default(),
+                },
+            })
+            .await;
+        loop {
+            if let Some(Event::RoomInfo { occupant_count, .. }) = bob_bus.next_event().await {
+                assert_eq!(occupant_count, 1);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn drop_user_gives_up_when_the_app_never_answers() {
+        let (app_sink, _gateway_source) = channel(16);
+        let alice = user("alice");
+        let mut bus = SessionBus::new(app_sink);
+
+        let left = bus
+            .drop_user(&alice, 0, std::time::Duration::from_millis(10))
+            .await;
+
+        assert!(!left);
+    }
+}